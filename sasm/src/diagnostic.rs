@@ -0,0 +1,125 @@
+use crate::source::{SourceMap, Span};
+
+pub struct Diagnostic {
+    pub message: String,
+    pub span: Option<Span>,
+    /// Other places in the sources that help explain the error.
+    pub notes: Vec<(String, Span)>,
+}
+
+impl Diagnostic {
+    pub fn note(&mut self, span: Span, message: impl Into<String>) -> &mut Self {
+        self.notes.push((message.into(), span));
+        return self;
+    }
+}
+
+/// Collects the errors found while assembling so that all of them can be
+/// reported at once.
+#[derive(Default)]
+pub struct Diagnostics {
+    errors: Vec<Diagnostic>,
+    /// The macro invocations being expanded, innermost last.
+    expansions: Vec<(String, Span)>,
+}
+
+impl Diagnostics {
+    pub fn error(&mut self, span: Span, message: impl Into<String>) -> &mut Diagnostic {
+        self.errors.push(Diagnostic {
+            message: message.into(),
+            span: Some(span),
+            notes: self.expansions.iter().rev().cloned().collect(),
+        });
+        // A recursive macro would repeat the same note for every level.
+        let error = self.errors.last_mut().expect("an error was just pushed");
+        error.notes.dedup();
+        return error;
+    }
+
+    /// Records an error that is not tied to a place in the sources.
+    pub fn global_error(&mut self, message: impl Into<String>) {
+        self.errors.push(Diagnostic {
+            message: message.into(),
+            span: None,
+            notes: Vec::new(),
+        });
+    }
+
+    /// Notes the macro invocation at `span` on every error found until the
+    /// matching `leave`.
+    pub fn enter(&mut self, span: Span, name: &str) {
+        let note = format!("in this expansion of `{}`", name);
+        self.expansions.push((note, span));
+    }
+
+    pub fn leave(&mut self) {
+        self.expansions.pop();
+    }
+
+    pub fn has_errors(&self) -> bool {
+        return !self.errors.is_empty();
+    }
+
+    pub fn count(&self) -> usize {
+        return self.errors.len();
+    }
+
+    #[cfg(test)]
+    pub fn messages(&self) -> Vec<&str> {
+        return self.errors.iter().map(|e| e.message.as_str()).collect();
+    }
+
+    /// Prints every error in the style of rustc:
+    ///
+    /// ```text
+    /// error: unknown instruction `lod`
+    ///  --> boot.S:12:5
+    ///    |
+    /// 12 |     lod r0, r1
+    ///    |     ^^^
+    /// ```
+    ///
+    /// Errors are printed in source order, whichever pass found them.
+    pub fn emit(&self, sources: &SourceMap) {
+        let mut errors: Vec<&Diagnostic> = self.errors.iter().collect();
+        errors.sort_by_key(|error| error.span.map(|span| (span.file, span.start)));
+        for error in errors {
+            eprintln!("error: {}", error.message);
+            if let Some(span) = error.span {
+                eprint!("{}", render_span(sources, span));
+            }
+            for (message, span) in error.notes.iter() {
+                eprintln!("note: {}", message);
+                eprint!("{}", render_span(sources, *span));
+            }
+            eprintln!();
+        }
+    }
+}
+
+fn render_span(sources: &SourceMap, span: Span) -> String {
+    let file = sources.get(span.file);
+    let (line, column) = file.position(span.start);
+    let text = file.line(line);
+    let gutter = " ".repeat(line.to_string().len());
+    // Spans running past the end of the line are cut at the line break.
+    let len = file.text[span.start..span.end]
+        .lines()
+        .next()
+        .map_or(0, |s| s.chars().count())
+        .max(1);
+    let mut out = String::new();
+    out.push_str(&format!(
+        "{}--> {}:{}:{}\n",
+        gutter, file.name, line, column
+    ));
+    out.push_str(&format!("{} |\n", gutter));
+    out.push_str(&format!("{} | {}\n", line, text));
+    let indent: String = text
+        .chars()
+        .take(column - 1)
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    out.push_str(&format!("{} | {}{}\n", gutter, indent, "^".repeat(len)));
+    return out;
+}
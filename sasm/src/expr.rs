@@ -0,0 +1,299 @@
+use crate::source::{SourceMap, Span, Spanned};
+use crate::symbols::{SymbolKind, SymbolTable};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UnaryOp {
+    Neg,
+    Not,
+    LogicalNot,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BinaryOp {
+    Mul,
+    Div,
+    Rem,
+    Add,
+    Sub,
+    Shl,
+    Shr,
+    And,
+    Xor,
+    Or,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    LogicalAnd,
+    LogicalOr,
+}
+
+impl BinaryOp {
+    /// Maps an operator lexeme to its operation and binding power. Higher
+    /// binds tighter, as in C.
+    pub fn from_punct(punct: &str) -> Option<(BinaryOp, u8)> {
+        match punct {
+            "*" => return Some((BinaryOp::Mul, 9)),
+            "/" => return Some((BinaryOp::Div, 9)),
+            "%" => return Some((BinaryOp::Rem, 9)),
+            "+" => return Some((BinaryOp::Add, 8)),
+            "-" => return Some((BinaryOp::Sub, 8)),
+            "<<" => return Some((BinaryOp::Shl, 7)),
+            ">>" => return Some((BinaryOp::Shr, 7)),
+            "<" => return Some((BinaryOp::Lt, 6)),
+            "<=" => return Some((BinaryOp::Le, 6)),
+            ">" => return Some((BinaryOp::Gt, 6)),
+            ">=" => return Some((BinaryOp::Ge, 6)),
+            "==" => return Some((BinaryOp::Eq, 5)),
+            "!=" => return Some((BinaryOp::Ne, 5)),
+            "&" => return Some((BinaryOp::And, 4)),
+            "^" => return Some((BinaryOp::Xor, 3)),
+            "|" => return Some((BinaryOp::Or, 2)),
+            "&&" => return Some((BinaryOp::LogicalAnd, 1)),
+            "||" => return Some((BinaryOp::LogicalOr, 0)),
+            _ => return None,
+        }
+    }
+}
+
+/// Functions that can be called in expressions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Function {
+    /// The high byte of a 16 bit value.
+    Hi,
+    /// The low byte of a 16 bit value.
+    Lo,
+}
+
+impl Function {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "hi" => return Some(Function::Hi),
+            "lo" => return Some(Function::Lo),
+            _ => return None,
+        }
+    }
+}
+
+/// A constant expression. Symbols are looked up when it is evaluated, so
+/// it may refer to labels defined further down.
+#[derive(Clone, Debug)]
+pub enum Expr {
+    Number(u64),
+    Symbol(String),
+    Unary(UnaryOp, Box<Spanned<Expr>>),
+    Binary(BinaryOp, Box<Spanned<Expr>>, Box<Spanned<Expr>>),
+    Call(Function, Box<Spanned<Expr>>),
+}
+
+/// Evaluates `expr` with 64 bit two's complement arithmetic. Symbols that
+/// cannot be found are named as written in `sources`.
+pub fn evaluate(
+    expr: &Spanned<Expr>,
+    symbols: &SymbolTable,
+    sources: &SourceMap,
+) -> Result<i64, (Span, String)> {
+    match &expr.node {
+        Expr::Number(n) => return Ok(*n as i64),
+        Expr::Symbol(name) => match symbols.get(name) {
+            Some(symbol) => return Ok(symbol.value),
+            None => {
+                let message = format!("cannot find symbol `{}`", sources.text(expr.span));
+                return Err((expr.span, message));
+            }
+        },
+        Expr::Unary(op, operand) => {
+            let value = evaluate(operand, symbols, sources)?;
+            match op {
+                UnaryOp::Neg => return Ok(value.wrapping_neg()),
+                UnaryOp::Not => return Ok(!value),
+                UnaryOp::LogicalNot => return Ok((value == 0) as i64),
+            }
+        }
+        Expr::Call(function, argument) => {
+            let value = evaluate(argument, symbols, sources)?;
+            match function {
+                Function::Hi => return Ok((value >> 8) & 0xFF),
+                Function::Lo => return Ok(value & 0xFF),
+            }
+        }
+        Expr::Binary(op, left, right) => {
+            let a = evaluate(left, symbols, sources)?;
+            let b = evaluate(right, symbols, sources)?;
+            let value = match op {
+                BinaryOp::Mul => Some(a.wrapping_mul(b)),
+                BinaryOp::Div => a.checked_div(b),
+                BinaryOp::Rem => a.checked_rem(b),
+                BinaryOp::Add => Some(a.wrapping_add(b)),
+                BinaryOp::Sub => Some(a.wrapping_sub(b)),
+                BinaryOp::Shl => u32::try_from(b).ok().and_then(|b| a.checked_shl(b)),
+                BinaryOp::Shr => u32::try_from(b).ok().and_then(|b| a.checked_shr(b)),
+                BinaryOp::And => Some(a & b),
+                BinaryOp::Xor => Some(a ^ b),
+                BinaryOp::Or => Some(a | b),
+                // Comparisons and logical operators give 1 for true and 0 for false.
+                BinaryOp::Eq => Some((a == b) as i64),
+                BinaryOp::Ne => Some((a != b) as i64),
+                BinaryOp::Lt => Some((a < b) as i64),
+                BinaryOp::Le => Some((a <= b) as i64),
+                BinaryOp::Gt => Some((a > b) as i64),
+                BinaryOp::Ge => Some((a >= b) as i64),
+                BinaryOp::LogicalAnd => Some((a != 0 && b != 0) as i64),
+                BinaryOp::LogicalOr => Some((a != 0 || b != 0) as i64),
+            };
+            let message = match op {
+                BinaryOp::Div | BinaryOp::Rem if b == 0 => "division by zero".to_string(),
+                BinaryOp::Shl | BinaryOp::Shr => format!("cannot shift by {}", b),
+                _ => "arithmetic overflow".to_string(),
+            };
+            return value.ok_or((expr.span, message));
+        }
+    }
+}
+
+/// Replaces the variables in `expr` with their current value. Variables may
+/// be redefined, so an expression evaluated later must not see a value set
+/// after it.
+pub fn fold_variables(expr: &mut Expr, symbols: &SymbolTable) {
+    match expr {
+        Expr::Number(_) => {}
+        Expr::Symbol(name) => {
+            if let Some(symbol) = symbols.get(name) {
+                if symbol.kind == SymbolKind::Variable {
+                    *expr = Expr::Number(symbol.value as u64);
+                }
+            }
+        }
+        Expr::Unary(_, operand) | Expr::Call(_, operand) => {
+            fold_variables(&mut operand.node, symbols)
+        }
+        Expr::Binary(_, left, right) => {
+            fold_variables(&mut left.node, symbols);
+            fold_variables(&mut right.node, symbols);
+        }
+    }
+}
+
+/// Truncates `value` to `bits` if it fits them either as an unsigned or as
+/// a two's complement number.
+pub fn truncate(value: i64, bits: u32) -> Option<u64> {
+    let max = (1i64 << bits) - 1;
+    let min = -(1i64 << (bits - 1));
+    if value < min || value > max {
+        return None;
+    }
+    return Some(value as u64 & max as u64);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::symbols::Symbol;
+
+    fn number(n: i64) -> Spanned<Expr> {
+        return Spanned::new(Expr::Number(n as u64), Span::default());
+    }
+
+    fn symbol(name: &str) -> Spanned<Expr> {
+        return Spanned::new(Expr::Symbol(name.to_string()), Span::default());
+    }
+
+    fn binary(op: BinaryOp, a: Spanned<Expr>, b: Spanned<Expr>) -> Spanned<Expr> {
+        let expr = Expr::Binary(op, Box::new(a), Box::new(b));
+        return Spanned::new(expr, Span::default());
+    }
+
+    fn eval(expr: &Spanned<Expr>) -> Result<i64, String> {
+        let sources = SourceMap::default();
+        let symbols = SymbolTable::default();
+        return evaluate(expr, &symbols, &sources).map_err(|(_, message)| message);
+    }
+
+    fn eval_binary(op: BinaryOp, a: i64, b: i64) -> Result<i64, String> {
+        return eval(&binary(op, number(a), number(b)));
+    }
+
+    #[test]
+    fn division_by_zero_is_an_error() {
+        assert_eq!(eval_binary(BinaryOp::Div, 7, 2), Ok(3));
+        assert_eq!(eval_binary(BinaryOp::Rem, -7, 2), Ok(-1));
+        let zero = Err("division by zero".to_string());
+        assert_eq!(eval_binary(BinaryOp::Div, 1, 0), zero);
+        assert_eq!(eval_binary(BinaryOp::Rem, 1, 0), zero);
+        let overflow = Err("arithmetic overflow".to_string());
+        assert_eq!(eval_binary(BinaryOp::Div, i64::MIN, -1), overflow);
+    }
+
+    #[test]
+    fn shifts_are_bounded_by_the_width() {
+        assert_eq!(eval_binary(BinaryOp::Shl, 1, 4), Ok(16));
+        assert_eq!(eval_binary(BinaryOp::Shr, -16, 2), Ok(-4));
+        assert_eq!(eval_binary(BinaryOp::Shl, 1, 63), Ok(i64::MIN));
+        let message = |b| Err(format!("cannot shift by {}", b));
+        assert_eq!(eval_binary(BinaryOp::Shl, 1, 64), message(64));
+        assert_eq!(eval_binary(BinaryOp::Shr, 1, -1), message(-1));
+    }
+
+    #[test]
+    fn functions_select_a_byte() {
+        let call =
+            |function, n| Spanned::new(Expr::Call(function, Box::new(number(n))), Span::default());
+        assert_eq!(eval(&call(Function::Hi, 0x1234)), Ok(0x12));
+        assert_eq!(eval(&call(Function::Lo, 0x1234)), Ok(0x34));
+    }
+
+    #[test]
+    fn labels_are_looked_up_by_name() {
+        let mut symbols = SymbolTable::default();
+        for (name, value) in [("start", 0x10), ("end", 0x30)] {
+            let symbol = Symbol {
+                kind: SymbolKind::Label,
+                value,
+                span: Span::default(),
+            };
+            assert!(symbols.define(name, symbol).is_ok());
+        }
+        let length = binary(BinaryOp::Sub, symbol("end"), symbol("start"));
+        let sources = SourceMap::default();
+        let value = evaluate(&length, &symbols, &sources).map_err(|e| e.1);
+        assert_eq!(value, Ok(0x20));
+    }
+
+    #[test]
+    fn missing_symbols_are_named_as_written() {
+        let mut sources = SourceMap::default();
+        let file = sources.add("test.S".to_string(), "ldl r0, .loop".to_string());
+        let span = Span::new(file, 8, 13);
+        let expr = Spanned::new(Expr::Symbol("main.loop".to_string()), span);
+        let result = evaluate(&expr, &SymbolTable::default(), &sources);
+        assert_eq!(
+            result.map_err(|e| e.1),
+            Err("cannot find symbol `.loop`".to_string())
+        );
+    }
+
+    #[test]
+    fn truncates_to_signed_or_unsigned_fields() {
+        assert_eq!(truncate(0xFF, 8), Some(0xFF));
+        assert_eq!(truncate(-1, 8), Some(0xFF));
+        assert_eq!(truncate(-128, 8), Some(0x80));
+        assert_eq!(truncate(0x100, 8), None);
+        assert_eq!(truncate(-129, 8), None);
+    }
+
+    #[test]
+    fn comparisons_give_one_or_zero() {
+        assert_eq!(eval_binary(BinaryOp::Lt, -1, 0), Ok(1));
+        assert_eq!(eval_binary(BinaryOp::Ge, -1, 0), Ok(0));
+        assert_eq!(eval_binary(BinaryOp::Ne, 3, 3), Ok(0));
+        assert_eq!(eval_binary(BinaryOp::LogicalAnd, 2, 4), Ok(1));
+        assert_eq!(eval_binary(BinaryOp::LogicalOr, 0, 0), Ok(0));
+        let not = Spanned::new(
+            Expr::Unary(UnaryOp::LogicalNot, Box::new(number(5))),
+            Span::default(),
+        );
+        assert_eq!(eval(&not), Ok(0));
+    }
+}
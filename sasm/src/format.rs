@@ -0,0 +1,138 @@
+use crate::Executable;
+
+const RECORD_SIZE: usize = 16;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    Binary,
+    IntelHex,
+    SRecord,
+}
+
+impl OutputFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        return match name {
+            "bin" | "binary" => Some(Self::Binary),
+            "ihex" | "hex" => Some(Self::IntelHex),
+            "srec" | "s19" => Some(Self::SRecord),
+            _ => None,
+        };
+    }
+}
+
+/// Encodes the written segments of `exec` as Intel HEX data records
+/// followed by an end-of-file record.
+pub fn to_intel_hex(exec: &Executable) -> String {
+    let mut out = String::new();
+    for (address, bytes) in exec.segments() {
+        for (i, chunk) in bytes.chunks(RECORD_SIZE).enumerate() {
+            let address = address.wrapping_add((i * RECORD_SIZE) as u16);
+            out.push_str(&intel_hex_record(0x00, address, chunk));
+        }
+    }
+    out.push_str(&intel_hex_record(0x01, 0, &[]));
+    return out;
+}
+
+fn intel_hex_record(kind: u8, address: u16, data: &[u8]) -> String {
+    let mut record = vec![data.len() as u8, (address >> 8) as u8, address as u8, kind];
+    record.extend_from_slice(data);
+    let sum = record.iter().fold(0u8, |acc, b| acc.wrapping_add(*b));
+    record.push(sum.wrapping_neg());
+    return format!(":{}\n", to_hex_digits(&record));
+}
+
+/// Encodes the written segments of `exec` as Motorola S-records: an S0
+/// header, S1 data records, an S5 record count and an S9 terminator.
+pub fn to_srecord(exec: &Executable, header: &str) -> String {
+    let mut out = String::new();
+    let mut count = 0;
+    out.push_str(&srecord('0', 0, header.as_bytes()));
+    for (address, bytes) in exec.segments() {
+        for (i, chunk) in bytes.chunks(RECORD_SIZE).enumerate() {
+            let address = address.wrapping_add((i * RECORD_SIZE) as u16);
+            out.push_str(&srecord('1', address, chunk));
+            count += 1;
+        }
+    }
+    out.push_str(&srecord('5', count as u16, &[]));
+    out.push_str(&srecord('9', 0, &[]));
+    return out;
+}
+
+fn srecord(kind: char, address: u16, data: &[u8]) -> String {
+    let mut record = vec![(data.len() + 3) as u8, (address >> 8) as u8, address as u8];
+    record.extend_from_slice(data);
+    let sum = record.iter().fold(0u8, |acc, b| acc.wrapping_add(*b));
+    record.push(!sum);
+    return format!("S{}{}\n", kind, to_hex_digits(&record));
+}
+
+fn to_hex_digits(bytes: &[u8]) -> String {
+    return bytes.iter().map(|b| format!("{:0>2X}", b)).collect();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn executable(address: u16, words: &[u16]) -> Executable {
+        let mut exec = Executable::new();
+        exec.set_address(address);
+        for word in words.iter() {
+            exec.push_short(*word);
+        }
+        return exec;
+    }
+
+    /// Whether the bytes of a record, checksum included, add up to `sum`.
+    fn sums_to(record: &str, sum: u8) -> bool {
+        // Intel HEX records start with `:`, S-records with `S` and their type.
+        let digits = match record.strip_prefix(':') {
+            Some(digits) => digits,
+            None => &record[2..],
+        };
+        let bytes = (0..digits.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&digits[i..i + 2], 16).expect("hex digits"));
+        return bytes.fold(0u8, |acc, b| acc.wrapping_add(b)) == sum;
+    }
+
+    #[test]
+    fn intel_hex_records_have_checksums() {
+        let hex = to_intel_hex(&executable(0x0100, &[0x0201, 0x0403]));
+        assert_eq!(hex, ":0401000001020304F1\n:00000001FF\n");
+    }
+
+    #[test]
+    fn intel_hex_splits_data_and_skips_gaps() {
+        let mut exec = executable(0x0000, &[0xAAAA; 10]);
+        exec.set_address(0x0040);
+        exec.push_short(0x5555);
+        let hex = to_intel_hex(&exec);
+        let records: Vec<&str> = hex.lines().collect();
+        assert_eq!(records.len(), 4);
+        assert!(records[0].starts_with(":10000000"));
+        assert!(records[1].starts_with(":04001000"));
+        assert!(records[2].starts_with(":02004000"));
+        assert!(records.iter().all(|record| sums_to(record, 0)));
+    }
+
+    #[test]
+    fn srecords_have_checksums_and_a_count() {
+        let srec = to_srecord(&executable(0x0100, &[0x0201, 0x0403]), "t");
+        assert_eq!(
+            srec,
+            "S00400007487\nS107010001020304ED\nS5030001FB\nS9030000FC\n"
+        );
+    }
+
+    #[test]
+    fn srecord_checksums_complement_the_sum() {
+        let srec = to_srecord(&executable(0x1234, &[0xFFFF; 20]), "image");
+        let records: Vec<&str> = srec.lines().collect();
+        assert_eq!(records.len(), 6);
+        assert_eq!(records[4], "S5030003F9");
+        assert!(records.iter().all(|record| sums_to(record, 0xFF)));
+    }
+}
@@ -0,0 +1,367 @@
+use crate::diagnostic::Diagnostics;
+use crate::source::{SourceFile, Span};
+use std::iter::Peekable;
+use std::str::CharIndices;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum LexemeKind {
+    /// Mnemonics, directives, registers and symbol names.
+    Ident(String),
+    Int(u64),
+    /// A string literal with its escapes resolved.
+    Str(Vec<u8>),
+    Comma,
+    Colon,
+    /// Operators and parentheses used in expressions.
+    Punct(&'static str),
+    Newline,
+}
+
+impl LexemeKind {
+    /// How the lexeme is named in error messages.
+    pub fn describe(&self) -> String {
+        match self {
+            LexemeKind::Ident(name) => return format!("`{}`", name),
+            LexemeKind::Int(n) => return format!("`{}`", n),
+            LexemeKind::Str(_) => return "a string".to_string(),
+            LexemeKind::Comma => return "`,`".to_string(),
+            LexemeKind::Colon => return "`:`".to_string(),
+            LexemeKind::Punct(punct) => return format!("`{}`", punct),
+            LexemeKind::Newline => return "end of line".to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Lexeme {
+    pub kind: LexemeKind,
+    pub span: Span,
+}
+
+fn is_ident_char(c: char) -> bool {
+    return c.is_ascii_alphanumeric() || c == '_' || c == '.';
+}
+
+/// Splits `source` into lines of lexemes, leaving out blank ones. Every
+/// line, including the last one, ends with a `Newline`. Comments are dropped:
+/// `;` and `//` run to the end of the line and `/* */` may span several.
+/// Characters that do not start a lexeme are reported and skipped.
+pub fn tokenize(file: usize, source: &SourceFile, diags: &mut Diagnostics) -> Vec<Vec<Lexeme>> {
+    let text = source.text.as_str();
+    let mut lexemes = Vec::new();
+    let mut chars = text.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        let mut end = start + c.len_utf8();
+        let kind = match c {
+            '\n' => LexemeKind::Newline,
+            ',' => LexemeKind::Comma,
+            ':' => LexemeKind::Colon,
+            c if c.is_whitespace() => continue,
+            ';' => {
+                skip_line(&mut chars);
+                continue;
+            }
+            '/' if chars.peek().is_some_and(|(_, c)| *c == '/') => {
+                skip_line(&mut chars);
+                continue;
+            }
+            '/' if chars.peek().is_some_and(|(_, c)| *c == '*') => {
+                chars.next();
+                let mut closed = false;
+                let mut lines = false;
+                while let Some((i, c)) = chars.next() {
+                    end = i + c.len_utf8();
+                    if c == '*' && chars.peek().is_some_and(|(_, c)| *c == '/') {
+                        chars.next();
+                        end += 1;
+                        closed = true;
+                        break;
+                    }
+                    lines |= c == '\n';
+                }
+                if !closed {
+                    let span = Span::new(file, start, start + 2);
+                    diags.error(span, "unterminated block comment");
+                }
+                // A comment spanning lines still ends the statement before it.
+                if !lines {
+                    continue;
+                }
+                LexemeKind::Newline
+            }
+            c if is_ident_char(c) => {
+                while let Some((i, c)) = chars.peek() {
+                    if !is_ident_char(*c) {
+                        break;
+                    }
+                    end = *i + c.len_utf8();
+                    chars.next();
+                }
+                let word = &text[start..end];
+                if !c.is_ascii_digit() {
+                    LexemeKind::Ident(word.to_string())
+                } else {
+                    match parse_int(word) {
+                        Some(value) => LexemeKind::Int(value),
+                        None if is_anonymous_reference(word) => LexemeKind::Ident(word.to_string()),
+                        None => {
+                            let span = Span::new(file, start, end);
+                            diags.error(span, format!("invalid number `{}`", word));
+                            LexemeKind::Int(0)
+                        }
+                    }
+                }
+            }
+            '"' => {
+                let mut value = Vec::new();
+                let mut closed = false;
+                while let Some((i, c)) = chars.next_if(|(_, c)| *c != '\n') {
+                    end = i + c.len_utf8();
+                    match c {
+                        '"' => {
+                            closed = true;
+                            break;
+                        }
+                        '\\' => match escape(&mut chars, i) {
+                            Ok((byte, next)) => {
+                                end = next;
+                                value.push(byte);
+                            }
+                            Err(next) => {
+                                end = next;
+                                let span = Span::new(file, i, end);
+                                let message = format!("unknown escape `{}`", &text[i..end]);
+                                diags.error(span, message);
+                            }
+                        },
+                        c => value.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+                    }
+                }
+                if !closed {
+                    let span = Span::new(file, start, end);
+                    diags.error(span, "unterminated string");
+                }
+                LexemeKind::Str(value)
+            }
+            c => match punct(c, chars.peek().map(|(_, next)| *next)) {
+                Some(punct) => {
+                    if punct.len() == 2 {
+                        chars.next();
+                        end += 1;
+                    }
+                    LexemeKind::Punct(punct)
+                }
+                None => {
+                    let span = Span::new(file, start, end);
+                    diags.error(span, format!("unexpected character `{}`", c));
+                    continue;
+                }
+            },
+        };
+        lexemes.push(Lexeme {
+            kind,
+            span: Span::new(file, start, end),
+        });
+    }
+    let end = text.len();
+    if lexemes
+        .last()
+        .is_some_and(|l| l.kind != LexemeKind::Newline)
+    {
+        lexemes.push(Lexeme {
+            kind: LexemeKind::Newline,
+            span: Span::new(file, end, end),
+        });
+    }
+    let lines = lexemes.split_inclusive(|l| l.kind == LexemeKind::Newline);
+    return lines
+        .filter(|line| line.len() > 1)
+        .map(<[Lexeme]>::to_vec)
+        .collect();
+}
+
+/// Operators and parentheses, the ones made of two characters first.
+const PUNCTUATION: [&str; 22] = [
+    "<<", ">>", "<=", ">=", "==", "!=", "&&", "||", "+", "-", "*", "/", "%", "&", "|", "^", "~",
+    "!", "<", ">", "(", ")",
+];
+
+/// Returns the punctuation starting with `c`, preferring the longest.
+fn punct(c: char, next: Option<char>) -> Option<&'static str> {
+    let mut pair = String::from(c);
+    pair.extend(next);
+    let found = PUNCTUATION
+        .iter()
+        .find(|p| **p == pair || p.len() == 1 && p.starts_with(c));
+    return found.copied();
+}
+
+/// Reads the escape sequence after the backslash at `start` in a string:
+/// `\n`, `\r`, `\t`, `\0`, `\\`, `\"` or `\xHH`. Returns the byte and the
+/// offset just past the sequence, or only the offset if it is not valid.
+fn escape(chars: &mut Peekable<CharIndices>, start: usize) -> Result<(u8, usize), usize> {
+    let (i, c) = match chars.next_if(|(_, c)| *c != '\n') {
+        Some(next) => next,
+        None => return Err(start + 1),
+    };
+    let mut end = i + c.len_utf8();
+    let byte = match c {
+        'n' => b'\n',
+        'r' => b'\r',
+        't' => b'\t',
+        '0' => 0,
+        '\\' => b'\\',
+        '"' => b'"',
+        'x' => {
+            let mut value = 0;
+            for _ in 0..2 {
+                match chars.next_if(|(_, c)| c.is_ascii_hexdigit()) {
+                    Some((i, digit)) => {
+                        end = i + 1;
+                        value = value * 16 + digit.to_digit(16).unwrap_or(0) as u8;
+                    }
+                    None => return Err(end),
+                }
+            }
+            value
+        }
+        _ => return Err(end),
+    };
+    return Ok((byte, end));
+}
+
+/// Whether `word` refers to an anonymous label, as in `1b` or `1f`.
+pub fn is_anonymous_reference(word: &str) -> bool {
+    let digits = match word.strip_suffix('b').or_else(|| word.strip_suffix('f')) {
+        Some(digits) => digits,
+        None => return false,
+    };
+    return !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit());
+}
+
+/// Skips a line comment, leaving the line break for the caller.
+fn skip_line(chars: &mut Peekable<CharIndices>) {
+    while chars.next_if(|(_, c)| *c != '\n').is_some() {}
+}
+
+/// Parses a decimal, `0x` hexadecimal or `0b` binary literal.
+pub fn parse_int(text: &str) -> Option<u64> {
+    let lower = text.to_ascii_lowercase();
+    if let Some(hex) = lower.strip_prefix("0x") {
+        return u64::from_str_radix(hex, 16).ok();
+    }
+    if let Some(bin) = lower.strip_prefix("0b") {
+        return u64::from_str_radix(bin, 2).ok();
+    }
+    return lower.parse::<u64>().ok();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::SourceMap;
+
+    fn lex(text: &str) -> (Vec<Vec<LexemeKind>>, usize) {
+        let mut sources = SourceMap::default();
+        let file = sources.add("test.S".to_string(), text.to_string());
+        let mut diags = Diagnostics::default();
+        let lines = tokenize(file, sources.get(file), &mut diags);
+        let kinds = lines
+            .into_iter()
+            .map(|line| line.into_iter().map(|l| l.kind).collect())
+            .collect();
+        return (kinds, diags.count());
+    }
+
+    fn ident(name: &str) -> LexemeKind {
+        return LexemeKind::Ident(name.to_string());
+    }
+
+    #[test]
+    fn splits_lines_into_lexemes() {
+        let (lines, errors) = lex("start: ldi r0, 0x10\n\nhlt");
+        assert_eq!(errors, 0);
+        assert_eq!(
+            lines,
+            vec![
+                vec![
+                    ident("start"),
+                    LexemeKind::Colon,
+                    ident("ldi"),
+                    ident("r0"),
+                    LexemeKind::Comma,
+                    LexemeKind::Int(0x10),
+                    LexemeKind::Newline,
+                ],
+                vec![ident("hlt"), LexemeKind::Newline],
+            ]
+        );
+    }
+
+    #[test]
+    fn unexpected_characters_are_reported_and_skipped() {
+        let (lines, errors) = lex("hlt $");
+        assert_eq!(errors, 1);
+        assert_eq!(lines, vec![vec![ident("hlt"), LexemeKind::Newline]]);
+    }
+
+    #[test]
+    fn parses_integers() {
+        assert_eq!(parse_int("42"), Some(42));
+        assert_eq!(parse_int("0x1F"), Some(0x1F));
+        assert_eq!(parse_int("0XfF"), Some(0xFF));
+        assert_eq!(parse_int("0b101"), Some(5));
+        assert_eq!(parse_int("0b102"), None);
+        assert_eq!(parse_int("0x"), None);
+        assert_eq!(parse_int("12ab"), None);
+        assert_eq!(lex("ldi r0, 0xZZ").1, 1);
+    }
+
+    #[test]
+    fn comments_are_dropped() {
+        let (lines, errors) = lex("nop ; one\nnop // two\n// three\nnop /* four */ r0");
+        assert_eq!(errors, 0);
+        assert_eq!(
+            lines,
+            vec![
+                vec![ident("nop"), LexemeKind::Newline],
+                vec![ident("nop"), LexemeKind::Newline],
+                vec![ident("nop"), ident("r0"), LexemeKind::Newline],
+            ]
+        );
+    }
+
+    #[test]
+    fn block_comments_spanning_lines_end_the_statement() {
+        let (lines, errors) = lex("nop /* one\ntwo */ hlt");
+        assert_eq!(errors, 0);
+        assert_eq!(
+            lines,
+            vec![
+                vec![ident("nop"), LexemeKind::Newline],
+                vec![ident("hlt"), LexemeKind::Newline],
+            ]
+        );
+        assert_eq!(lex("nop /* open").1, 1);
+    }
+
+    #[test]
+    fn operators_prefer_the_longest_match() {
+        let (lines, errors) = lex("1 << 2 < 3 != !4");
+        assert_eq!(errors, 0);
+        assert_eq!(
+            lines[0],
+            vec![
+                LexemeKind::Int(1),
+                LexemeKind::Punct("<<"),
+                LexemeKind::Int(2),
+                LexemeKind::Punct("<"),
+                LexemeKind::Int(3),
+                LexemeKind::Punct("!="),
+                LexemeKind::Punct("!"),
+                LexemeKind::Int(4),
+                LexemeKind::Newline,
+            ]
+        );
+    }
+}
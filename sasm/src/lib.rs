@@ -1,7 +1,30 @@
 use num_derive::ToPrimitive;
-use std::{fs, path::PathBuf, str::FromStr};
+use num_traits::ToPrimitive as _;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub mod diagnostic;
+pub mod expr;
+pub mod format;
+pub mod lexer;
+pub mod macros;
+pub mod parser;
+pub mod source;
+pub mod symbols;
+
+use diagnostic::Diagnostics;
+use expr::Expr;
+use format::OutputFormat;
+use lexer::{Lexeme, LexemeKind};
+use macros::Macro;
+use parser::{Operand, Operation, Statement};
+use source::{SourceMap, Span, Spanned};
+use symbols::{Symbol, SymbolKind, SymbolTable};
 
 const TRAMPOLINE_SIZE: u64 = 4 * 2;
+/// The number of addressable bytes.
+const ADDRESS_SPACE: u64 = 1 << 16;
 
 #[macro_export]
 macro_rules! critical {
@@ -13,6 +36,13 @@ macro_rules! critical {
     };
 }
 
+/// Every instruction and pseudo instruction, which macros cannot redefine.
+const INSTRUCTIONS: [&str; 26] = [
+    "nop", "and", "not", "add", "sub", "inc", "dec", "ldb", "ldw", "mov", "ldi", "stb", "stw",
+    "jmp", "jnz", "shr", "shl", "test", "setf", "clrf", "push", "pop", "ldl", "call", "callf",
+    "ret",
+];
+
 #[derive(ToPrimitive)]
 pub enum RegisterId {
     R7 = 0x07,
@@ -22,10 +52,10 @@ pub enum RegisterId {
 }
 
 pub enum Token {
-    // Literals
-    LABEL(u64, u16),
     // Assembler directives
-    SHORT(u64, bool),
+    BYTE(Vec<Spanned<Expr>>),
+    SHORT(Vec<Spanned<Expr>>),
+    BYTES(Vec<u8>),
     ADDR(u16),
     // Instructions
     NOP,
@@ -38,27 +68,29 @@ pub enum Token {
     LDB(u16, u16),
     LDW(u16, u16),
     MOV(u16, u16),
-    LDI(u16, u8),
+    LDI(u16, Spanned<Expr>),
     STB(u16, u16),
     STW(u16, u16),
     JMP(u16),
     JNZ(u16, u16),
-    SHR(u16, u8),
-    SHL(u16, u8),
-    TEST(u8),
-    SETF(u8),
-    CLRF(u8),
+    SHR(u16, Spanned<Expr>),
+    SHL(u16, Spanned<Expr>),
+    TEST(Spanned<Expr>),
+    SETF(Spanned<Expr>),
+    CLRF(Spanned<Expr>),
     // Assembler pseudo instructions
     PUSH(u16),
     POP(u16),
-    LDL(u16, u64),
+    LDL(u16, Spanned<Expr>),
     CALL(u16, u16),
     CALLF(u16, u16, u16),
     RET(u16),
 }
 
+#[derive(Default)]
 pub struct Executable {
     bytes: Vec<u8>,
+    written: Vec<bool>,
     address: u16,
 }
 
@@ -66,25 +98,32 @@ impl Executable {
     pub fn new() -> Self {
         return Self {
             bytes: Vec::new(),
+            written: Vec::new(),
             address: 0,
         };
     }
 
     fn push_byte(&mut self, b: u8) {
-        let mut current_address = self.bytes.len() as u16;
-        if self.address > current_address {
-            for _ in current_address..self.address {
-                self.bytes.push(0);
-            }
-            current_address = self.address;
+        let address = self.address as usize;
+        if address > self.bytes.len() {
+            self.bytes.resize(address, 0);
+            self.written.resize(address, false);
         }
-        if self.address == current_address {
+        if address == self.bytes.len() {
             self.bytes.push(b);
+            self.written.push(true);
         } else {
-            self.bytes.remove(self.address as usize);
-            self.bytes.insert(self.address as usize, b);
+            self.bytes[address] = b;
+            self.written[address] = true;
+        }
+        // The last byte of memory may be written; nothing follows it.
+        self.address = self.address.wrapping_add(1);
+    }
+
+    pub fn push_bytes(&mut self, bytes: &[u8]) {
+        for b in bytes.iter() {
+            self.push_byte(*b);
         }
-        self.address += 1;
     }
 
     pub fn push_short(&mut self, s: u16) {
@@ -103,24 +142,93 @@ impl Executable {
     pub fn bytes(&self) -> &Vec<u8> {
         return &self.bytes;
     }
+
+    /// Returns every contiguous run of bytes that was actually written,
+    /// skipping the zero padding left behind by `.addr` jumps.
+    pub fn segments(&self) -> Vec<(u16, &[u8])> {
+        let mut segments = Vec::new();
+        let mut start = None;
+        for i in 0..=self.bytes.len() {
+            let written = i < self.written.len() && self.written[i];
+            match (start, written) {
+                (None, true) => start = Some(i),
+                (Some(s), false) => {
+                    segments.push((s as u16, &self.bytes[s..i]));
+                    start = None;
+                }
+                _ => {}
+            }
+        }
+        return segments;
+    }
+}
+
+/// An `.if` being assembled.
+struct Condition {
+    span: Span,
+    /// Whether the lines in the current branch are assembled.
+    active: bool,
+    /// Whether a branch has been assembled already, so `.else` is skipped.
+    taken: bool,
+    has_else: bool,
 }
 
 pub struct Job {
     files: Vec<String>,
+    include_dirs: Vec<String>,
+    /// The canonical path of every file read so far.
+    included: HashSet<PathBuf>,
     entry: String,
     output: String,
+    format: OutputFormat,
     trampoline: bool,
     address: u64,
+    overflowed: bool,
+    pub sources: SourceMap,
+    pub symbols: SymbolTable,
+    /// The last label not starting with `.`, which local labels belong to.
+    scope: String,
+    /// How many times each anonymous label `N:` has been defined so far.
+    anonymous: HashMap<u64, usize>,
+    /// The references `Nf` to anonymous labels that were not defined yet.
+    forward: Vec<Spanned<String>>,
+    macros: HashMap<String, Macro>,
+    /// How many macro expansions there have been so far.
+    expansions: usize,
+    /// Set once the recursion limit is hit. No macro is expanded after
+    /// that, as a macro invoking itself more than once would otherwise
+    /// take exponential time to reach the limit everywhere.
+    runaway: bool,
+    pub diagnostics: Diagnostics,
+}
+
+impl Default for Job {
+    fn default() -> Self {
+        return Self::new();
+    }
 }
 
 impl Job {
     pub fn new() -> Self {
         return Self {
             files: Vec::new(),
+            include_dirs: Vec::new(),
+            included: HashSet::new(),
             entry: "_start".to_string(),
             output: "a.out".to_string(),
+            format: OutputFormat::Binary,
             trampoline: false,
             address: 0,
+            overflowed: false,
+            sources: SourceMap::default(),
+            symbols: SymbolTable::default(),
+            scope: String::new(),
+            anonymous: HashMap::new(),
+            forward: Vec::new(),
+            macros: HashMap::new(),
+            expansions: 0,
+            runaway: false,
+            diagnostics: Diagnostics::default(),
         };
     }
 
@@ -128,6 +236,38 @@ impl Job {
         self.files.push(path);
     }
 
+    /// Defines the constant `NAME=value` given on the command line. The
+    /// value defaults to 1.
+    pub fn define_symbol(&mut self, definition: &str) {
+        let (name, value) = definition.split_once('=').unwrap_or((definition, "1"));
+        if name.is_empty() {
+            critical!("-D expects NAME[=VALUE].");
+        }
+        let value = match value.strip_prefix('-') {
+            Some(digits) => lexer::parse_int(digits).map(|n| (n as i64).wrapping_neg()),
+            None => lexer::parse_int(value).map(|n| n as i64),
+        };
+        let value = match value {
+            Some(value) => value,
+            None => critical!("Invalid value in `-D {}`.", definition),
+        };
+        // Errors about the symbol point at its definition on the command line.
+        let file = self
+            .sources
+            .add("<command line>".to_string(), format!("-D {}", definition));
+        let symbol = Symbol {
+            kind: SymbolKind::Constant,
+            value,
+            span: Span::new(file, 3, 3 + name.len()),
+        };
+        self.define(name, symbol);
+    }
+
+    /// Adds a directory to search for `.include` and `.incbin` files.
+    pub fn add_include_dir(&mut self, dir: String) {
+        self.include_dirs.push(dir);
+    }
+
     pub fn set_entry(&mut self, entry: String) {
         self.entry = entry;
     }
@@ -136,391 +276,1210 @@ impl Job {
         self.output = path;
     }
 
+    pub fn set_format(&mut self, format: OutputFormat) {
+        self.format = format;
+    }
+
     pub fn trampoline(&mut self) {
         self.address += TRAMPOLINE_SIZE;
         self.trampoline = true;
     }
 
-    fn get_lines(&self) -> Vec<String> {
+    /// Reads, parses and lowers every input file. Errors are collected in
+    /// `diagnostics` instead of stopping at the first one.
+    pub fn tokenize(&mut self) -> Vec<Spanned<Token>> {
         if self.files.is_empty() {
             critical!("No input file provided.");
         }
-        let mut code = Vec::new();
-        for path in self.files.iter() {
-            let content = read_file(path);
-            if !content.is_ascii() {
-                critical!("File `{}` is not ASCII.", path);
+        let mut tokens = Vec::new();
+        for path in self.files.clone() {
+            // Files are only assembled once, even if one of them was
+            // included by another one given before it.
+            if fs::canonicalize(&path).is_ok_and(|path| self.included.contains(&path)) {
+                continue;
             }
-            let lines = content.split("\n");
-            for line in lines.into_iter() {
-                if !line.trim().is_empty() {
-                    code.push(line.trim_start().trim_end().to_string());
-                }
+            match self.read_lines(Path::new(&path)) {
+                Ok(lines) => self.assemble(&lines, &mut tokens, 0),
+                Err(message) => self.diagnostics.global_error(message),
             }
         }
-        return code;
-    }
-
-    pub fn tokenize(&mut self) -> Vec<Token> {
-        let lines = self.get_lines();
-        let mut tokens = Vec::new();
-        for i in 0..lines.len() {
-            let line = match lines.get(i) {
-                Some(line) => line,
-                None => critical!("Failed to fetch line number {}.", i),
-            };
-            if self.address >= u16::MAX as u64 {
-                critical!("Exceeded maximum binary size! Fault line: `{}`.", line);
+        for reference in std::mem::take(&mut self.forward) {
+            if self.symbols.get(&reference.node).is_some() {
+                continue;
             }
-            tokens.push(self.gen_token(line.trim_end()));
+            let (n, _) = reference.node.split_once('@').unwrap_or_default();
+            let message = format!("no `{}:` label follows this reference", n);
+            self.diagnostics.error(reference.span, message);
+            // Stand in for the label so the reference is not reported again.
+            let symbol = Symbol {
+                kind: SymbolKind::Label,
+                value: 0,
+                span: reference.span,
+            };
+            let _ = self.symbols.define(&reference.node, symbol);
         }
-        if self.trampoline {
-            let id = calculate_label_id(self.entry.as_str());
-            tokens.insert(0, Token::LDL(0, id));
-            tokens.insert(1, Token::JMP(0));
+        if self.trampoline && self.symbols.get(&self.entry).is_none() {
+            let message = format!("entry label `{}` is not defined", self.entry);
+            self.diagnostics.global_error(message);
+        } else if self.trampoline {
+            let span = Span::default();
+            let entry = Spanned::new(Expr::Symbol(self.entry.clone()), span);
+            tokens.insert(0, Spanned::new(Token::LDL(0, entry), span));
+            tokens.insert(1, Spanned::new(Token::JMP(0), span));
         }
         return tokens;
     }
 
-    pub fn write_output(&self, exec: Executable) {
-        if let Ok(path) = PathBuf::from_str(self.output.as_str()) {
-            match fs::write(path, exec.bytes()) {
-                Ok(_) => println!("Wrote {} bytes.", exec.size()),
-                Err(err) => critical!(
-                    "An error occured when writing file `{}`:\n`{}`.",
-                    self.output,
-                    err.to_string()
-                ),
-            }
+    /// Prints the collected errors and exits if there are any.
+    pub fn check_errors(&self) {
+        let count = self.diagnostics.count();
+        if count == 0 {
             return;
         }
-        critical!("Failed to write output file `{}`.", self.output);
+        self.diagnostics.emit(&self.sources);
+        if count == 1 {
+            critical!("error: aborting due to previous error");
+        }
+        critical!("error: aborting due to {} previous errors", count);
     }
 
-    fn gen_token(&mut self, raw_line: &str) -> Token {
-        let line = match raw_line.split_once(' ') {
-            Some(parts) => (parts.0.to_string(), parts.1.replace(" ", "")),
-            None => (raw_line.to_string(), "".to_string()),
+    pub fn write_output(&self, exec: Executable) {
+        let path = PathBuf::from(self.output.as_str());
+        let contents = match self.format {
+            OutputFormat::Binary => exec.bytes().clone(),
+            OutputFormat::IntelHex => format::to_intel_hex(&exec).into_bytes(),
+            OutputFormat::SRecord => format::to_srecord(&exec, &self.output).into_bytes(),
         };
-        if line.0.starts_with('.') && !line.1.is_empty() {
-            return self.gen_directive_token(&line.0, &line.1);
+        match fs::write(path, &contents) {
+            Ok(_) => println!("Wrote {} bytes.", contents.len()),
+            Err(err) => critical!(
+                "An error occured when writing file `{}`:\n`{}`.",
+                self.output,
+                err
+            ),
         }
-        if line.0.ends_with(':') && line.1.is_empty() {
-            return self.gen_label_token(&line.0);
+    }
+
+    /// Reads and lexes the file at `path`, recording it as included.
+    fn read_lines(&mut self, path: &Path) -> Result<Vec<Vec<Lexeme>>, String> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(err) => return Err(format!("cannot read `{}`: {}", path.display(), err)),
+        };
+        if let Ok(canonical) = fs::canonicalize(path) {
+            self.included.insert(canonical);
         }
-        return self.gen_instruction_token(&line.0, &line.1);
+        let file = self.sources.add(path.display().to_string(), text);
+        return Ok(lexer::tokenize(
+            file,
+            self.sources.get(file),
+            &mut self.diagnostics,
+        ));
     }
 
-    fn gen_instruction_token(&mut self, instruction: &String, arguments: &String) -> Token {
-        let arguments = arguments.split(',').collect::<Vec<&str>>();
-        let assert_args_len_eq = |len| -> () {
-            let arglen = arguments.len();
-            if (len == 0 && (arglen > 1 || !arguments[0].is_empty())) || (len > 0 && arglen > len) {
-                critical!(
-                    "Too many arguments for assembler instruction `{}`.",
-                    instruction
-                );
-            } else if arglen < len {
-                critical!(
-                    "Not enough arguments for assembler instruction `{}`.",
-                    instruction
-                );
+    /// Finds the file named by `operand`, first next to the file the operand
+    /// is written in and then in the include directories.
+    fn find_file(&mut self, operand: &Spanned<Operand>) -> Option<PathBuf> {
+        let name = match &operand.node {
+            Operand::Str(name) => String::from_utf8_lossy(name).to_string(),
+            _ => {
+                let message = format!("expected a file name, found {}", self.describe(operand));
+                self.diagnostics.error(operand.span, message);
+                return None;
             }
         };
-        self.address += 2;
-        return match instruction.as_str() {
-            "nop" => {
-                assert_args_len_eq(0);
-                Token::NOP
-            }
-            "and" => {
-                assert_args_len_eq(2);
-                Token::AND(reg_name_to_num(arguments[0]), reg_name_to_num(arguments[1]))
-            }
-            "not" => {
-                assert_args_len_eq(1);
-                Token::NOT(reg_name_to_num(arguments[0]))
-            }
-            "add" => {
-                assert_args_len_eq(2);
-                Token::ADD(reg_name_to_num(arguments[0]), reg_name_to_num(arguments[1]))
-            }
-            "sub" => {
-                assert_args_len_eq(2);
-                Token::SUB(reg_name_to_num(arguments[0]), reg_name_to_num(arguments[1]))
-            }
-            "inc" => {
-                assert_args_len_eq(1);
-                Token::INC(reg_name_to_num(arguments[0]))
-            }
-            "dec" => {
-                assert_args_len_eq(1);
-                Token::DEC(reg_name_to_num(arguments[0]))
-            }
-            "ldb" => {
-                assert_args_len_eq(2);
-                Token::LDB(reg_name_to_num(arguments[0]), reg_name_to_num(arguments[1]))
-            }
-            "ldw" => {
-                assert_args_len_eq(2);
-                Token::LDW(reg_name_to_num(arguments[0]), reg_name_to_num(arguments[1]))
-            }
-            "mov" => {
-                assert_args_len_eq(2);
-                Token::MOV(reg_name_to_num(arguments[0]), reg_name_to_num(arguments[1]))
-            }
-            "ldi" => {
-                assert_args_len_eq(2);
-                Token::LDI(
-                    reg_name_to_num(arguments[0]),
-                    parse_int_from_string(arguments[1]),
-                )
+        let current = Path::new(&self.sources.get(operand.span.file).name);
+        let mut dirs = vec![current.parent().unwrap_or(Path::new("")).to_path_buf()];
+        dirs.extend(self.include_dirs.iter().map(PathBuf::from));
+        for dir in dirs.iter() {
+            let path = dir.join(&name);
+            if path.is_file() {
+                return Some(path);
             }
-            "stb" => {
-                assert_args_len_eq(2);
-                Token::STB(reg_name_to_num(arguments[0]), reg_name_to_num(arguments[1]))
-            }
-            "stw" => {
-                assert_args_len_eq(2);
-                Token::STW(reg_name_to_num(arguments[0]), reg_name_to_num(arguments[1]))
-            }
-            "jmp" => {
-                assert_args_len_eq(1);
-                Token::JMP(reg_name_to_num(arguments[0]))
-            }
-            "jnz" => {
-                assert_args_len_eq(2);
-                Token::JNZ(reg_name_to_num(arguments[0]), reg_name_to_num(arguments[1]))
+        }
+        let message = format!("cannot find `{}` in the include path", name);
+        self.diagnostics.error(operand.span, message);
+        return None;
+    }
+
+    /// Handles `.include "file"`. Every file is included at most once, so
+    /// shared files need no guards of their own.
+    fn include(&mut self, op: &Operation, tokens: &mut Vec<Spanned<Token>>, depth: usize) {
+        if !self.arity(op, 1) {
+            return;
+        }
+        let path = match self.find_file(&op.operands[0]) {
+            Some(path) => path,
+            None => return,
+        };
+        if fs::canonicalize(&path).is_ok_and(|path| self.included.contains(&path)) {
+            return;
+        }
+        match self.read_lines(&path) {
+            Ok(lines) => self.assemble(&lines, tokens, depth),
+            Err(message) => _ = self.diagnostics.error(op.operands[0].span, message),
+        }
+    }
+
+    /// Parses and lowers `lines`, defining and expanding macros on the way.
+    /// `depth` is how many macro expansions the lines are nested in.
+    fn assemble(&mut self, lines: &[Vec<Lexeme>], tokens: &mut Vec<Spanned<Token>>, depth: usize) {
+        let mut conditions = Vec::new();
+        let mut i = 0;
+        while i < lines.len() {
+            let line = &lines[i];
+            i += 1;
+            if self.conditional(line, &mut conditions)
+                || conditions.iter().any(|c: &Condition| !c.active)
+            {
+                continue;
             }
-            "shr" => {
-                assert_args_len_eq(2);
-                Token::SHR(
-                    reg_name_to_num(arguments[0]),
-                    parse_int_from_string(arguments[1]),
-                )
+            if macros::starts_with(line, ".macro") {
+                i += self.define_macro(line, &lines[i..]);
+            } else if macros::starts_with(line, ".endm") {
+                let message = "`.endm` without a matching `.macro`";
+                self.diagnostics.error(line[0].span, message);
+            } else if !self.invoke_macro(line, tokens, depth) {
+                let statement = parser::parse(line, &mut self.diagnostics);
+                self.lower(statement, tokens, depth);
             }
-            "shl" => {
-                assert_args_len_eq(2);
-                Token::SHL(
-                    reg_name_to_num(arguments[0]),
-                    parse_int_from_string(arguments[1]),
-                )
+        }
+        for condition in conditions.iter() {
+            let message = "`.if` without a matching `.endif`";
+            self.diagnostics.error(condition.span, message);
+        }
+    }
+
+    /// Handles `.if`, `.ifdef`, `.ifndef`, `.else` and `.endif`, returning
+    /// whether `line` is one of them. Conditions inside a branch that is not
+    /// assembled are not evaluated.
+    fn conditional(&mut self, line: &[Lexeme], conditions: &mut Vec<Condition>) -> bool {
+        let name = match &line[0].kind {
+            LexemeKind::Ident(name) => name.as_str(),
+            _ => return false,
+        };
+        let span = line[0].span;
+        let active = conditions.iter().all(|c| c.active);
+        match name {
+            ".if" | ".ifdef" | ".ifndef" => {
+                let taken = active && self.condition(line);
+                conditions.push(Condition {
+                    span,
+                    active: taken,
+                    taken: taken || !active,
+                    has_else: false,
+                });
+                return true;
             }
-            "test" => {
-                assert_args_len_eq(1);
-                Token::TEST(parse_int_from_string(arguments[0]))
+            ".else" => match conditions.last_mut() {
+                Some(condition) if !condition.has_else => {
+                    condition.active = !condition.taken;
+                    condition.taken = true;
+                    condition.has_else = true;
+                }
+                Some(condition) => {
+                    let previous = condition.span;
+                    self.diagnostics
+                        .error(span, "`.else` after `.else`")
+                        .note(previous, "the condition starts here");
+                }
+                None => {
+                    _ = self
+                        .diagnostics
+                        .error(span, "`.else` without a matching `.if`")
+                }
+            },
+            ".endif" => {
+                if conditions.pop().is_none() {
+                    self.diagnostics
+                        .error(span, "`.endif` without a matching `.if`");
+                }
             }
-            "setf" => {
-                assert_args_len_eq(1);
-                Token::SETF(parse_int_from_string(arguments[0]))
+            _ => return false,
+        }
+        if line[1].kind != LexemeKind::Newline {
+            let message = format!("expected end of line, found {}", line[1].kind.describe());
+            self.diagnostics.error(line[1].span, message);
+        }
+        return true;
+    }
+
+    /// Evaluates the condition of an `.if`, `.ifdef` or `.ifndef` line.
+    fn condition(&mut self, line: &[Lexeme]) -> bool {
+        let mut op = match parser::parse(line, &mut self.diagnostics).operation {
+            Some(op) => op,
+            None => return false,
+        };
+        self.qualify_operands(&mut op);
+        if !self.arity(&op, 1) {
+            return false;
+        }
+        let operand = &op.operands[0];
+        if op.name.node == ".if" {
+            return self.evaluate(operand).is_some_and(|value| value != 0);
+        }
+        let name = match &operand.node {
+            Operand::Expr(Expr::Symbol(name)) => name,
+            other => {
+                let message = format!("expected a symbol name, found {}", other.describe());
+                self.diagnostics.error(operand.span, message);
+                return false;
             }
-            "clrf" => {
-                assert_args_len_eq(1);
-                Token::CLRF(parse_int_from_string(arguments[0]))
+        };
+        let defined = self.symbols.get(name).is_some();
+        return defined == (op.name.node == ".ifdef");
+    }
+
+    /// Defines the macro starting with the `.macro` line `header` and taking
+    /// its body from `rest`. Returns how many lines of `rest` it used.
+    fn define_macro(&mut self, header: &[Lexeme], rest: &[Vec<Lexeme>]) -> usize {
+        let (len, used) = match macros::body_len(rest) {
+            Some(len) => (len, len + 1),
+            None => {
+                let message = "`.macro` without a matching `.endm`";
+                self.diagnostics.error(header[0].span, message);
+                (rest.len(), rest.len())
             }
-            _ => self.gen_pseudo_instruction_token(instruction, &arguments),
         };
+        let (name, params) = match macros::parse_header(header, &mut self.diagnostics) {
+            Some(header) => header,
+            None => return used,
+        };
+        if name.node.starts_with('.') || INSTRUCTIONS.contains(&name.node.as_str()) {
+            let message = format!("`{}` cannot be used as a macro name", name.node);
+            self.diagnostics.error(name.span, message);
+            return used;
+        }
+        if let Some(previous) = self.macros.get(&name.node) {
+            let message = format!("macro `{}` is defined multiple times", name.node);
+            self.diagnostics.error(name.span, message).note(
+                previous.name.span,
+                format!("`{}` is first defined here", name.node),
+            );
+            return used;
+        }
+        let body = rest[..len].to_vec();
+        self.macros
+            .insert(name.node.clone(), Macro::new(name, params, body));
+        return used;
     }
 
-    fn gen_pseudo_instruction_token(
+    /// Expands `line` if it invokes a macro, returning whether it does.
+    fn invoke_macro(
         &mut self,
-        instruction: &String,
-        arguments: &Vec<&str>,
-    ) -> Token {
-        let assert_args_len_eq = |len| -> () {
-            let arglen = arguments.len();
-            if arglen > len {
-                critical!(
-                    "Too many arguments for assembler instruction `{}`.",
-                    instruction
-                );
-            } else if arglen < len {
-                critical!(
-                    "Not enough arguments for assembler instruction `{}`.",
-                    instruction
-                );
-            }
-        };
-        return match instruction.as_str() {
+        line: &[Lexeme],
+        tokens: &mut Vec<Spanned<Token>>,
+        depth: usize,
+    ) -> bool {
+        let label = parser::label(line);
+        let start = if label.is_some() { 2 } else { 0 };
+        let name = match &line[start].kind {
+            LexemeKind::Ident(name) if self.macros.contains_key(name) => name.clone(),
+            _ => return false,
+        };
+        if let Some(label) = label {
+            self.define_label(Spanned::new(label, line[0].span));
+        }
+        if self.runaway {
+            return true;
+        }
+        let arguments = &line[start + 1..line.len() - 1];
+        let span = match arguments.last() {
+            Some(last) => line[start].span.to(last.span),
+            None => line[start].span,
+        };
+        if depth >= macros::RECURSION_LIMIT {
+            let message = format!("recursion limit reached while expanding `{}`", name);
+            self.diagnostics.error(span, message);
+            self.runaway = true;
+            return true;
+        }
+        let args = macros::split_args(arguments);
+        let mac = &self.macros[&name];
+        if args.len() != mac.params.len() {
+            let count = mac.params.len();
+            let message = format!(
+                "macro `{}` takes {} argument{} but {} {} given",
+                name,
+                count,
+                if count == 1 { "" } else { "s" },
+                args.len(),
+                if args.len() == 1 { "was" } else { "were" }
+            );
+            self.diagnostics
+                .error(span, message)
+                .note(mac.name.span, format!("`{}` is defined here", name));
+            return true;
+        }
+        if args.iter().any(Vec::is_empty) {
+            let message = format!("empty argument in invocation of `{}`", name);
+            self.diagnostics.error(span, message);
+            return true;
+        }
+        self.expansions += 1;
+        let lines = mac.expand(&args, self.expansions);
+        self.diagnostics.enter(span, &name);
+        self.assemble(&lines, tokens, depth + 1);
+        self.diagnostics.leave();
+        return true;
+    }
+
+    fn lower(&mut self, statement: Statement, tokens: &mut Vec<Spanned<Token>>, depth: usize) {
+        if let Some(label) = statement.label {
+            self.define_label(label);
+        }
+        let mut operation = match statement.operation {
+            Some(operation) => operation,
+            None => return,
+        };
+        self.qualify_operands(&mut operation);
+        if operation.name.node == ".include" {
+            self.include(&operation, tokens, depth);
+            return;
+        }
+        let token = if operation.name.node.starts_with('.') {
+            self.gen_directive_token(&operation)
+        } else {
+            self.gen_instruction_token(&operation)
+        };
+        // Anything past the end of the address space cannot be emitted.
+        // `address` is where the operation ends, so it may be right at it.
+        if self.address > ADDRESS_SPACE {
+            if !self.overflowed {
+                self.diagnostics
+                    .error(operation.span, "exceeded maximum binary size");
+                self.overflowed = true;
+            }
+            return;
+        }
+        if let Some(token) = token {
+            tokens.push(Spanned::new(token, operation.span));
+        }
+    }
+
+    fn gen_instruction_token(&mut self, op: &Operation) -> Option<Token> {
+        use RegisterId::*;
+        self.address += 2;
+        return match op.name.node.as_str() {
+            "nop" => self.arity(op, 0).then_some(Token::NOP),
+            "and" => self
+                .two_registers(op, R7, R7)
+                .map(|(x, y)| Token::AND(x, y)),
+            "not" => self.one_register(op, R7).map(Token::NOT),
+            "add" => self
+                .two_registers(op, R7, R7)
+                .map(|(x, y)| Token::ADD(x, y)),
+            "sub" => self
+                .two_registers(op, R7, R7)
+                .map(|(x, y)| Token::SUB(x, y)),
+            "inc" => self.one_register(op, SP).map(Token::INC),
+            "dec" => self.one_register(op, SP).map(Token::DEC),
+            "ldb" => self
+                .two_registers(op, R7, SP)
+                .map(|(x, y)| Token::LDB(x, y)),
+            "ldw" => self
+                .two_registers(op, R7, SP)
+                .map(|(x, y)| Token::LDW(x, y)),
+            "mov" => self
+                .two_registers(op, C1, C1)
+                .map(|(x, y)| Token::MOV(x, y)),
+            "ldi" => self
+                .register_and_value(op, R7)
+                .map(|(x, n)| Token::LDI(x, n)),
+            "stb" => self
+                .two_registers(op, SP, R7)
+                .map(|(y, x)| Token::STB(y, x)),
+            "stw" => self
+                .two_registers(op, SP, R7)
+                .map(|(y, x)| Token::STW(y, x)),
+            "jmp" => self.one_register(op, SP).map(Token::JMP),
+            "jnz" => self
+                .two_registers(op, SP, R7)
+                .map(|(x, y)| Token::JNZ(x, y)),
+            "shr" => self
+                .register_and_value(op, R7)
+                .map(|(x, n)| Token::SHR(x, n)),
+            "shl" => self
+                .register_and_value(op, R7)
+                .map(|(x, n)| Token::SHL(x, n)),
+            "test" => self.one_value(op).map(Token::TEST),
+            "setf" => self.one_value(op).map(Token::SETF),
+            "clrf" => self.one_value(op).map(Token::CLRF),
+            _ => self.gen_pseudo_instruction_token(op),
+        };
+    }
+
+    fn gen_pseudo_instruction_token(&mut self, op: &Operation) -> Option<Token> {
+        use RegisterId::*;
+        return match op.name.node.as_str() {
             "push" => {
-                assert_args_len_eq(1);
                 self.address += 2 * 3 - 2;
-                Token::PUSH(reg_name_to_num(arguments[0]))
+                self.one_register(op, R7).map(Token::PUSH)
             }
             "pop" => {
-                assert_args_len_eq(1);
                 self.address += 2 * 3 - 2;
-                Token::POP(reg_name_to_num(arguments[0]))
+                self.one_register(op, R7).map(Token::POP)
             }
             "ldl" => {
-                assert_args_len_eq(2);
                 self.address += 2 * 3 - 2;
-                let value = match arguments[0].parse::<u16>() {
-                    Ok(v) => v as u64,
-                    Err(_) => {
-                        let trimmed = arguments[1].trim_start_matches("0x");
-                        match u16::from_str_radix(trimmed, 16) {
-                            Ok(v) => v as u64,
-                            Err(_) => calculate_label_id(trimmed),
-                        }
-                    }
-                };
-                Token::LDL(reg_name_to_num(arguments[0]), value)
+                self.register_and_value(op, R7)
+                    .map(|(x, k)| Token::LDL(x, k))
             }
             "call" => {
-                assert_args_len_eq(1);
                 self.address += 2 * 17 - 2;
-                Token::CALL(reg_name_to_num(arguments[0]), self.address as u16)
+                let address = self.address as u16;
+                self.one_register(op, R7).map(|x| Token::CALL(x, address))
             }
             "callf" => {
-                assert_args_len_eq(2);
                 self.address += 2 * 7 - 2;
-                Token::CALLF(
-                    reg_name_to_num(arguments[0]),
-                    reg_name_to_num(arguments[1]),
-                    self.address as u16,
-                )
+                let address = self.address as u16;
+                let (x, y) = self.two_registers(op, SP, R7)?;
+                if x == y {
+                    let message = "`callf` needs two different registers";
+                    self.diagnostics.error(op.span, message);
+                    return None;
+                }
+                Some(Token::CALLF(x, y, address))
             }
             "ret" => {
-                assert_args_len_eq(1);
                 self.address += 2 * 4 - 2;
-                Token::RET(reg_name_to_num(arguments[0]))
-            }
-            _ => critical!("Invalid instruction `{}`.", instruction),
-        };
-    }
-
-    fn gen_directive_token(&mut self, directive: &String, arguments: &String) -> Token {
-        let directive = match directive.strip_prefix('.') {
-            Some(str) => str,
-            None => critical!("Failed to remove semicolon from label (`{}`).", directive),
-        };
-        let arguments = arguments.split(',').collect::<Vec<&str>>();
-        let assert_args_len_eq = |len| -> () {
-            let arglen = arguments.len();
-            if arglen > len {
-                critical!(
-                    "Too many arguments for assembler directive `.{}`.",
-                    directive
-                );
-            } else if arglen < len {
-                critical!(
-                    "Not enough arguments for assembler instruction `.{}`.",
-                    directive
-                );
-            }
-        };
-        return match directive {
-            "short" => {
-                assert_args_len_eq(1);
-                let mut is_label = false;
-                let trimmed = arguments[0].trim_start_matches("0x");
-                let short = match trimmed.parse::<u16>() {
-                    Ok(v) => v as u64,
-                    Err(_) => match u16::from_str_radix(trimmed, 16) {
-                        Ok(v) => v as u64,
-                        Err(_) => {
-                            is_label = true;
-                            calculate_label_id(trimmed)
-                        }
-                    },
-                };
-                self.address += 2;
-                Token::SHORT(short, is_label)
-            }
-            "addr" => {
-                assert_args_len_eq(1);
-                let address = match arguments[0].parse::<u16>() {
-                    Ok(v) => v as u64,
-                    Err(_) => {
-                        let trimmed = arguments[0].trim_start_matches("0x");
-                        match u16::from_str_radix(trimmed, 16) {
-                            Ok(v) => v as u64,
-                            Err(_) => critical!("Invalid address `{}`", trimmed),
-                        }
-                    }
-                };
+                self.one_register(op, R7).map(Token::RET)
+            }
+            name => {
+                // Undo the size assumed by `gen_instruction_token`.
+                self.address -= 2;
+                let message = format!("unknown instruction `{}`", name);
+                self.diagnostics.error(op.name.span, message);
+                None
+            }
+        };
+    }
+
+    fn gen_directive_token(&mut self, op: &Operation) -> Option<Token> {
+        return match op.name.node.as_str() {
+            ".byte" => {
+                let values = self.values(op)?;
+                self.address += values.len() as u64;
+                Some(Token::BYTE(values))
+            }
+            ".short" | ".word" => {
+                let values = self.values(op)?;
+                self.address += 2 * values.len() as u64;
+                Some(Token::SHORT(values))
+            }
+            ".ascii" | ".asciz" => {
+                let bytes = self.strings(op, op.name.node == ".asciz")?;
+                self.address += bytes.len() as u64;
+                Some(Token::BYTES(bytes))
+            }
+            ".fill" => self.gen_fill_token(op),
+            ".space" => {
+                let (size, fill) = self.count_and_fill(op)?;
+                self.address += size;
+                Some(Token::BYTES(vec![fill; size as usize]))
+            }
+            ".align" => {
+                let (align, fill) = self.count_and_fill(op)?;
+                if align == 0 {
+                    let message = "alignment must be at least 1";
+                    self.diagnostics.error(op.operands[0].span, message);
+                    return None;
+                }
+                let padding = (align - self.address % align) % align;
+                self.address += padding;
+                Some(Token::BYTES(vec![fill; padding as usize]))
+            }
+            ".addr" => {
+                let address = self.one_number(op, u16::MAX as u64)?;
                 if address % 2 != 0 {
-                    critical!("Address {:0>4X} is not 2 byte aligned.", address);
-                } else if address > u16::MAX as u64 {
-                    critical!(
-                        "Address {:0>4X} is higher than the maximum allowed.",
-                        address
-                    );
+                    let message = format!("address {:0>4X} is not 2 byte aligned", address);
+                    self.diagnostics.error(op.operands[0].span, message);
+                    return None;
                 }
                 self.address = address;
-                Token::ADDR(address as u16)
+                Some(Token::ADDR(address as u16))
+            }
+            ".incbin" => self.gen_incbin_token(op),
+            ".equ" => {
+                self.define_constant(op, SymbolKind::Constant);
+                None
+            }
+            ".set" => {
+                self.define_constant(op, SymbolKind::Variable);
+                None
+            }
+            ".if" | ".ifdef" | ".ifndef" | ".else" | ".endif" | ".macro" | ".endm" => {
+                let message = format!("`{}` must start a line", op.name.node);
+                self.diagnostics.error(op.name.span, message);
+                None
+            }
+            name => {
+                let message = format!("unknown directive `{}`", name);
+                self.diagnostics.error(op.name.span, message);
+                None
             }
-            _ => critical!("Invalid directive `.{}`.", directive),
         };
     }
 
-    fn gen_label_token(&mut self, line: &String) -> Token {
-        let label = match line.strip_suffix(':') {
-            Some(str) => str,
-            None => critical!("Failed to remove semicolon from label (`{}`).", line),
+    /// Returns the values of a `.byte` or `.word` list.
+    fn values(&mut self, op: &Operation) -> Option<Vec<Spanned<Expr>>> {
+        if op.operands.is_empty() {
+            let message = format!("`{}` takes at least 1 operand", op.name.node);
+            self.diagnostics.error(op.span, message);
+            return None;
+        }
+        let values: Vec<_> = op.operands.iter().map(|o| self.value(o)).collect();
+        return values.into_iter().collect();
+    }
+
+    /// Returns the bytes of the strings of an `.ascii` or `.asciz` list,
+    /// ending each with a zero if `terminate` is set.
+    fn strings(&mut self, op: &Operation, terminate: bool) -> Option<Vec<u8>> {
+        if op.operands.is_empty() {
+            let message = format!("`{}` takes at least 1 operand", op.name.node);
+            self.diagnostics.error(op.span, message);
+            return None;
+        }
+        let mut bytes = Vec::new();
+        let mut valid = true;
+        for operand in op.operands.iter() {
+            match &operand.node {
+                Operand::Str(string) => {
+                    bytes.extend_from_slice(string);
+                    if terminate {
+                        bytes.push(0);
+                    }
+                }
+                _ => {
+                    let message = format!("expected a string, found {}", self.describe(operand));
+                    self.diagnostics.error(operand.span, message);
+                    valid = false;
+                }
+            }
+        }
+        return valid.then_some(bytes);
+    }
+
+    /// Reads the operands of `.space size[, fill]` and `.align n[, fill]`.
+    fn count_and_fill(&mut self, op: &Operation) -> Option<(u64, u8)> {
+        let count = op.operands.len();
+        if !(1..=2).contains(&count) {
+            let message = format!(
+                "`{}` takes 1 or 2 operands but {} were given",
+                op.name.node, count
+            );
+            self.diagnostics.error(op.span, message);
+            return None;
+        }
+        let size = self.number(&op.operands[0], u16::MAX as u64);
+        let fill = match op.operands.get(1) {
+            Some(fill) => self.number(fill, u8::MAX as u64),
+            None => Some(0),
+        };
+        return Some((size?, fill? as u8));
+    }
+
+    /// Handles `.fill repeat[, size[, value]]`, which repeats a byte or a
+    /// 16 bit word.
+    fn gen_fill_token(&mut self, op: &Operation) -> Option<Token> {
+        let count = op.operands.len();
+        if !(1..=3).contains(&count) {
+            let message = format!("`.fill` takes 1 to 3 operands but {} were given", count);
+            self.diagnostics.error(op.span, message);
+            return None;
+        }
+        let repeat = self.number(&op.operands[0], u16::MAX as u64);
+        let size = match op.operands.get(1) {
+            Some(size) => self.number(size, u16::MAX as u64),
+            None => Some(1),
+        };
+        let value = match op.operands.get(2) {
+            Some(value) => self.value(value),
+            None => Some(Spanned::new(Expr::Number(0), op.span)),
+        };
+        let (repeat, size, value) = (repeat?, size?, value?);
+        if size != 1 && size != 2 {
+            let message = "the size of `.fill` values must be 1 or 2";
+            self.diagnostics.error(op.operands[1].span, message);
+            return None;
+        }
+        let values = vec![value; repeat as usize];
+        self.address += repeat * size;
+        if size == 1 {
+            return Some(Token::BYTE(values));
+        }
+        return Some(Token::SHORT(values));
+    }
+
+    /// Handles `.incbin "file"[, offset[, length]]`.
+    fn gen_incbin_token(&mut self, op: &Operation) -> Option<Token> {
+        let count = op.operands.len();
+        if !(1..=3).contains(&count) {
+            let message = format!("`.incbin` takes 1 to 3 operands but {} were given", count);
+            self.diagnostics.error(op.span, message);
+            return None;
+        }
+        let path = self.find_file(&op.operands[0])?;
+        let offset = match op.operands.get(1) {
+            Some(offset) => self.number(offset, u32::MAX as u64)? as usize,
+            None => 0,
+        };
+        let data = match fs::read(&path) {
+            Ok(data) => data,
+            Err(err) => {
+                let message = format!("cannot read `{}`: {}", path.display(), err);
+                self.diagnostics.error(op.operands[0].span, message);
+                return None;
+            }
+        };
+        let len = match op.operands.get(2) {
+            Some(len) => self.number(len, u32::MAX as u64)? as usize,
+            None => data.len().saturating_sub(offset),
+        };
+        if offset + len > data.len() {
+            let message = format!(
+                "range {}..{} is outside of `{}`, which is {} bytes long",
+                offset,
+                offset + len,
+                path.display(),
+                data.len()
+            );
+            self.diagnostics.error(op.span, message);
+            return None;
+        }
+        self.address += len as u64;
+        return Some(Token::BYTES(data[offset..offset + len].to_vec()));
+    }
+
+    fn define_label(&mut self, label: Spanned<String>) {
+        let name = if label.node.starts_with('.') {
+            format!("{}{}", self.scope, label.node)
+        } else if let Ok(n) = label.node.parse::<u64>() {
+            let count = self.anonymous.entry(n).or_insert(0);
+            *count += 1;
+            format!("{}@{}", n, *count - 1)
+        } else {
+            // Labels of macro expansions do not start a new scope.
+            if !label.node.contains('@') {
+                self.scope = label.node.clone();
+            }
+            label.node.clone()
         };
-        if self.trampoline && self.entry.as_str() == label && self.address == TRAMPOLINE_SIZE {
+        let name = name.as_str();
+        if self.trampoline && self.entry.as_str() == name && self.address == TRAMPOLINE_SIZE {
             self.address -= TRAMPOLINE_SIZE;
             self.trampoline = false;
         }
-        return Token::LABEL(calculate_label_id(label), self.address as u16);
+        let symbol = Symbol {
+            kind: SymbolKind::Label,
+            value: self.address as i64,
+            span: label.span,
+        };
+        self.define(name, symbol);
     }
-}
 
-fn calculate_label_id(label: &str) -> u64 {
-    let label = label.as_bytes();
-    let mut hash = 0;
-    for j in (0..label.len()).step_by(8) {
-        let mut mask = 0;
-        for i in 0..8 {
-            let index = i + j;
-            let value = if index < label.len() { label[index] } else { 0 };
-            mask |= (value as u64) << i * 8;
+    /// Replaces the local and anonymous labels in the expressions of `op`
+    /// with the names they are defined under.
+    fn qualify_operands(&mut self, op: &mut Operation) {
+        for operand in op.operands.iter_mut() {
+            if let Operand::Expr(expr) = &mut operand.node {
+                self.qualify(expr, operand.span);
+            }
         }
-        hash ^= mask;
     }
-    return hash;
-}
 
-fn parse_int_from_string<F: std::str::FromStr>(string: &str) -> F {
-    return match string.parse::<F>() {
-        Ok(val) => val,
-        Err(_) => critical!("Error parsing `{}` into unsigned integer.", string),
-    };
-}
+    fn qualify(&mut self, expr: &mut Expr, span: Span) {
+        let name = match expr {
+            Expr::Number(_) => return,
+            Expr::Symbol(name) => name,
+            Expr::Unary(_, operand) | Expr::Call(_, operand) => {
+                return self.qualify(&mut operand.node, operand.span)
+            }
+            Expr::Binary(_, left, right) => {
+                self.qualify(&mut left.node, left.span);
+                return self.qualify(&mut right.node, right.span);
+            }
+        };
+        if name.starts_with('.') {
+            *name = format!("{}{}", self.scope, name);
+            return;
+        }
+        if !lexer::is_anonymous_reference(name) {
+            return;
+        }
+        let (digits, direction) = name.split_at(name.len() - 1);
+        let n = match digits.parse::<u64>() {
+            Ok(n) => n,
+            Err(_) => {
+                self.diagnostics
+                    .error(span, "anonymous label number is too large");
+                *expr = Expr::Number(0);
+                return;
+            }
+        };
+        let count = self.anonymous.get(&n).copied().unwrap_or(0);
+        if direction == "f" {
+            *name = format!("{}@{}", n, count);
+            self.forward.push(Spanned::new(name.clone(), span));
+        } else if count > 0 {
+            *name = format!("{}@{}", n, count - 1);
+        } else {
+            let message = format!("no `{}:` label precedes this reference", n);
+            self.diagnostics.error(span, message);
+            *expr = Expr::Number(0);
+        }
+    }
 
-fn reg_name_to_num(name: &str) -> u16 {
-    let name = name.trim();
-    if name == "sp" {
-        return RegisterId::SP as u16;
-    }
-    let num = match name.get(1..2) {
-        Some(num) => parse_int_from_string(num),
-        None => critical!(
-            "Failed to obtain register number (input string was `{}`).",
-            name
-        ),
-    };
-    if name.starts_with("r") {
-        return num;
+    /// Handles `.equ NAME, value` and `.set NAME, value`. The value is
+    /// evaluated right away, so it may only refer to symbols defined before.
+    fn define_constant(&mut self, op: &Operation, kind: SymbolKind) {
+        if !self.arity(op, 2) {
+            return;
+        }
+        let name = &op.operands[0];
+        let value = self.evaluate(&op.operands[1]);
+        let name = match &name.node {
+            Operand::Expr(Expr::Symbol(symbol)) => Spanned::new(symbol.as_str(), name.span),
+            other => {
+                let message = format!("expected a symbol name, found {}", other.describe());
+                self.diagnostics.error(name.span, message);
+                return;
+            }
+        };
+        let value = match value {
+            Some(value) => value,
+            None => return,
+        };
+        let symbol = Symbol {
+            kind,
+            value,
+            span: name.span,
+        };
+        self.define(name.node, symbol);
+    }
+
+    fn define(&mut self, name: &str, symbol: Symbol) {
+        let span = symbol.span;
+        if let Err(previous) = self.symbols.define(name, symbol) {
+            // Name the symbol as written, not by its qualified name.
+            let name = self.sources.text(span);
+            let message = format!("symbol `{}` is defined multiple times", name);
+            let note = format!("`{}` is first defined here", name);
+            self.diagnostics.error(span, message).note(previous, note);
+        }
+    }
+
+    /// Checks that `op` has exactly `count` operands.
+    fn arity(&mut self, op: &Operation, count: usize) -> bool {
+        let given = op.operands.len();
+        if given == count {
+            return true;
+        }
+        let message = format!(
+            "`{}` takes {} operand{} but {} {} given",
+            op.name.node,
+            count,
+            if count == 1 { "" } else { "s" },
+            given,
+            if given == 1 { "was" } else { "were" }
+        );
+        self.diagnostics.error(op.span, message);
+        return false;
+    }
+
+    fn one_register(&mut self, op: &Operation, max: RegisterId) -> Option<u16> {
+        if !self.arity(op, 1) {
+            return None;
+        }
+        return self.register(&op.operands[0], max);
+    }
+
+    fn two_registers(
+        &mut self,
+        op: &Operation,
+        x: RegisterId,
+        y: RegisterId,
+    ) -> Option<(u16, u16)> {
+        if !self.arity(op, 2) {
+            return None;
+        }
+        let x = self.register(&op.operands[0], x);
+        let y = self.register(&op.operands[1], y);
+        return Some((x?, y?));
+    }
+
+    fn register_and_value(
+        &mut self,
+        op: &Operation,
+        x: RegisterId,
+    ) -> Option<(u16, Spanned<Expr>)> {
+        if !self.arity(op, 2) {
+            return None;
+        }
+        let x = self.register(&op.operands[0], x);
+        let value = self.value(&op.operands[1]);
+        return Some((x?, value?));
+    }
+
+    fn one_value(&mut self, op: &Operation) -> Option<Spanned<Expr>> {
+        if !self.arity(op, 1) {
+            return None;
+        }
+        return self.value(&op.operands[0]);
+    }
+
+    fn one_number(&mut self, op: &Operation, max: u64) -> Option<u64> {
+        if !self.arity(op, 1) {
+            return None;
+        }
+        return self.number(&op.operands[0], max);
+    }
+
+    /// How `operand` is named in error messages. Symbols are named as
+    /// written rather than by the name they were qualified to.
+    fn describe(&self, operand: &Spanned<Operand>) -> String {
+        if let Operand::Expr(Expr::Symbol(_)) = operand.node {
+            return format!("the symbol `{}`", self.sources.text(operand.span));
+        }
+        return operand.node.describe();
+    }
+
+    /// Returns the register named by `operand` if it is at most `max`.
+    fn register(&mut self, operand: &Spanned<Operand>, max: RegisterId) -> Option<u16> {
+        let expected = match max {
+            RegisterId::R7 => "r0 to r7",
+            RegisterId::SP => "r0 to r7 or sp",
+            RegisterId::C0 => "r0 to r7, sp or c0",
+            RegisterId::C1 => "r0 to r7, sp, c0 or c1",
+        };
+        let message = match &operand.node {
+            Operand::Register(id) if max.to_u16().is_some_and(|max| *id <= max) => {
+                return Some(*id);
+            }
+            Operand::Register(_) => format!("register out of range, expected {}", expected),
+            _ => format!("expected a register, found {}", self.describe(operand)),
+        };
+        self.diagnostics.error(operand.span, message);
+        return None;
+    }
+
+    /// Evaluates `operand` right away, so it may only refer to symbols
+    /// defined before it.
+    fn evaluate(&mut self, operand: &Spanned<Operand>) -> Option<i64> {
+        let expr = self.value(operand)?;
+        match expr::evaluate(&expr, &self.symbols, &self.sources) {
+            Ok(value) => return Some(value),
+            Err((span, message)) => {
+                self.diagnostics.error(span, message);
+                return None;
+            }
+        }
+    }
+
+    fn number(&mut self, operand: &Spanned<Operand>, max: u64) -> Option<u64> {
+        let value = self.evaluate(operand)?;
+        if (0..=max as i64).contains(&value) {
+            return Some(value as u64);
+        }
+        let message = format!("`{}` is out of range, expected 0 to {}", value, max);
+        self.diagnostics.error(operand.span, message);
+        return None;
     }
-    if name.starts_with('c') {
-        return num + RegisterId::C0 as u16;
+
+    /// Returns an expression to evaluate once every label is known.
+    fn value(&mut self, operand: &Spanned<Operand>) -> Option<Spanned<Expr>> {
+        match &operand.node {
+            Operand::Expr(expr) => {
+                let mut expr = expr.clone();
+                expr::fold_variables(&mut expr, &self.symbols);
+                return Some(Spanned::new(expr, operand.span));
+            }
+            _ => {
+                let message = format!("expected a number, found {}", self.describe(operand));
+                self.diagnostics.error(operand.span, message);
+                return None;
+            }
+        }
     }
-    critical!("Invalid register `{}`.", name)
 }
 
-fn read_file(file: &String) -> String {
-    if let Ok(path) = PathBuf::from_str(file.as_str()) {
-        match fs::read_to_string(path) {
-            Ok(str) => return str,
-            Err(err) => critical!(
-                "An error occured when reading file `{}`:\n`{}`.",
-                file,
-                err.to_string()
-            ),
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Assembles `text` as the only input file, leaving the errors found in
+    /// the returned job.
+    fn assemble(name: &str, text: &str) -> Job {
+        return assemble_with(name, text, |_| {});
+    }
+
+    /// Like `assemble`, with `setup` applied to the job first.
+    fn assemble_with(name: &str, text: &str, setup: impl FnOnce(&mut Job)) -> Job {
+        let file = format!("sasm-{}-{}.S", std::process::id(), name);
+        let path = std::env::temp_dir().join(file);
+        fs::write(&path, text).expect("the source can be written");
+        let mut job = Job::new();
+        setup(&mut job);
+        job.add_file(path.to_string_lossy().into_owned());
+        job.tokenize();
+        let _ = fs::remove_file(&path);
+        return job;
+    }
+
+    #[test]
+    fn macros_expand_with_their_own_labels() {
+        let text = ".macro wait n\nldi r0, n\nagain: dec r0\nldl r1, again\njnz r0, r1\n.endm\nwait 1\nwait 2\n";
+        let job = assemble("wait", text);
+        assert_eq!(job.diagnostics.count(), 0);
+        assert!(job.symbols.get("again@1").is_some());
+        assert!(job.symbols.get("again@2").is_some());
+    }
+
+    #[test]
+    fn recursive_macros_stop_at_the_limit() {
+        let job = assemble("recursion", ".macro forever\nforever\n.endm\nforever\n");
+        assert_eq!(job.diagnostics.count(), 1);
+    }
+
+    /// Writes `files` into a fresh directory named after `name` and returns
+    /// its path.
+    fn write_files(name: &str, files: &[(&str, &[u8])]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("sasm-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        for (file, contents) in files.iter() {
+            let path = dir.join(file);
+            fs::create_dir_all(path.parent().expect("a directory")).expect("it can be created");
+            fs::write(path, contents).expect("the file can be written");
         }
+        return dir;
+    }
+
+    #[test]
+    fn files_are_included_once_from_the_search_path() {
+        let main = b".include \"shared.S\"\n.include \"lib.S\"\n.include \"shared.S\"\nend:\n";
+        let files: [(&str, &[u8]); 3] = [
+            ("main.S", main),
+            ("shared.S", b"shared: .short 1\n"),
+            ("sub/lib.S", b"lib: .short 2\n"),
+        ];
+        let dir = write_files("include", &files);
+        let mut job = Job::new();
+        job.add_include_dir(dir.join("sub").to_string_lossy().into_owned());
+        job.add_file(dir.join("main.S").to_string_lossy().into_owned());
+        job.tokenize();
+        let _ = fs::remove_dir_all(&dir);
+        assert_eq!(job.diagnostics.count(), 0);
+        assert_eq!(job.symbols.get("lib").map(|s| s.value), Some(2));
+        assert_eq!(job.symbols.get("end").map(|s| s.value), Some(4));
+    }
+
+    #[test]
+    fn incbin_copies_a_range_of_bytes() {
+        let main =
+            b".incbin \"data.bin\", 2, 3\nafter:\n.incbin \"data.bin\", 8, 4\n.incbin \"none\"\n";
+        let data: Vec<u8> = (1..=10).collect();
+        let dir = write_files("incbin", &[("main.S", main), ("data.bin", &data)]);
+        let mut job = Job::new();
+        job.add_file(dir.join("main.S").to_string_lossy().into_owned());
+        let tokens = job.tokenize();
+        let _ = fs::remove_dir_all(&dir);
+        assert_eq!(job.diagnostics.count(), 2);
+        assert_eq!(job.symbols.get("after").map(|s| s.value), Some(3));
+        assert!(matches!(&tokens[0].node, Token::BYTES(bytes) if bytes == &[3, 4, 5]));
+    }
+
+    #[test]
+    fn expansion_stops_everywhere_once_the_limit_is_hit() {
+        // Without stopping, this would expand 2^64 times.
+        let text = ".macro boom\nboom\nboom\n.endm\nboom\nboom\n";
+        let job = assemble("runaway", text);
+        assert_eq!(job.diagnostics.count(), 1);
+    }
+
+    #[test]
+    fn only_the_taken_branch_is_assembled() {
+        let text = ".if 1 > 2\nno:\n.else\nyes:\n.endif\n.ifdef yes\nalso:\n.endif\n.ifndef no\nnot:\n.endif\n";
+        let job = assemble("branches", text);
+        assert_eq!(job.diagnostics.count(), 0);
+        assert!(job.symbols.get("no").is_none());
+        assert!(job.symbols.get("yes").is_some());
+        assert!(job.symbols.get("also").is_some());
+        assert!(job.symbols.get("not").is_some());
+    }
+
+    #[test]
+    fn conditions_in_skipped_branches_are_not_evaluated() {
+        let text = ".if 0\n.if 1 / 0\ninner:\n.endif\n.else\nouter:\n.endif\n";
+        let job = assemble("nested", text);
+        assert_eq!(job.diagnostics.count(), 0);
+        assert!(job.symbols.get("inner").is_none());
+        assert!(job.symbols.get("outer").is_some());
+    }
+
+    #[test]
+    fn unbalanced_conditions_are_reported() {
+        assert_eq!(assemble("else", ".else\n").diagnostics.count(), 1);
+        assert_eq!(assemble("endif", ".endif\n").diagnostics.count(), 1);
+        assert_eq!(assemble("unclosed", ".if 1\n").diagnostics.count(), 1);
+        let twice = ".if 1\n.else\n.else\n.endif\n";
+        assert_eq!(assemble("twice", twice).diagnostics.count(), 1);
+    }
+
+    #[test]
+    fn command_line_symbols_can_be_tested() {
+        let text = ".if DEBUG == 2 && LEVEL < 0\ndebug:\n.endif\n";
+        let job = assemble_with("defines", text, |job| {
+            job.define_symbol("DEBUG=0x2");
+            job.define_symbol("LEVEL=-1");
+        });
+        assert_eq!(job.diagnostics.count(), 0);
+        assert!(job.symbols.get("debug").is_some());
+        let job = assemble_with("default", ".equ X, FLAG\n", |job| job.define_symbol("FLAG"));
+        assert_eq!(job.symbols.get("X").map(|s| s.value), Some(1));
+    }
+
+    fn address_of(job: &Job, label: &str) -> Option<i64> {
+        return job.symbols.get(label).map(|symbol| symbol.value);
+    }
+
+    #[test]
+    fn data_directives_advance_the_address() {
+        let text = r#"
+            .byte 1, 2, 3
+        a:  .ascii "ab", "c"
+        b:  .asciz "x"
+        c:  .byte 0
+            .align 4, 0xFF
+        d:  .space 3, 0xFF
+        e:  .fill 2, 2, 0x1234
+        f:  .word a, b
+        g:
+        "#;
+        let job = assemble("data", text);
+        assert_eq!(job.diagnostics.count(), 0);
+        let labels = ["a", "b", "c", "d", "e", "f", "g"];
+        let addresses: Vec<_> = labels.iter().map(|l| address_of(&job, l)).collect();
+        let expected = [3, 6, 8, 12, 15, 19, 23];
+        assert_eq!(addresses, expected.map(Some));
+    }
+
+    #[test]
+    fn bad_data_operands_are_reported() {
+        assert_eq!(assemble("fill", ".fill 1, 3\n").diagnostics.count(), 1);
+        assert_eq!(assemble("ascii", ".ascii 5\n").diagnostics.count(), 1);
+        assert_eq!(assemble("byte", ".byte\n").diagnostics.count(), 1);
+        assert_eq!(assemble("align", ".align 1, 2, 3\n").diagnostics.count(), 1);
+    }
+
+    /// Returns the symbol names the `.word` lines of `text` refer to.
+    fn word_references(name: &str, text: &str) -> (Job, Vec<String>) {
+        let file = format!("sasm-{}-{}.S", std::process::id(), name);
+        let path = std::env::temp_dir().join(file);
+        fs::write(&path, text).expect("the source can be written");
+        let mut job = Job::new();
+        job.add_file(path.to_string_lossy().into_owned());
+        let tokens = job.tokenize();
+        let _ = fs::remove_file(&path);
+        let mut names = Vec::new();
+        for token in tokens.iter() {
+            if let Token::SHORT(values) = &token.node {
+                for value in values.iter() {
+                    if let Expr::Symbol(name) = &value.node {
+                        names.push(name.clone());
+                    }
+                }
+            }
+        }
+        return (job, names);
+    }
+
+    #[test]
+    fn local_labels_belong_to_the_previous_global_label() {
+        let text = "first:\n.loop: .word .loop\nsecond:\n.loop: .word .loop, first.loop\n";
+        let (job, names) = word_references("local", text);
+        assert_eq!(job.diagnostics.count(), 0);
+        assert_eq!(names, ["first.loop", "second.loop", "first.loop"]);
+        assert_eq!(address_of(&job, "first.loop"), Some(0));
+        assert_eq!(address_of(&job, "second.loop"), Some(2));
+    }
+
+    #[test]
+    fn anonymous_labels_refer_to_the_nearest_definition() {
+        let text = "1: .word 1f\n1: .word 1b, 1f\n2: .word 1b, 2b\n1:\n";
+        let (job, names) = word_references("anonymous", text);
+        assert_eq!(job.diagnostics.count(), 0);
+        assert_eq!(names, ["1@1", "1@1", "1@2", "1@1", "2@0"]);
+        assert_eq!(address_of(&job, "1@2"), Some(10));
+    }
+
+    #[test]
+    fn anonymous_references_need_a_label() {
+        assert_eq!(
+            word_references("back", ".word 1b\n").0.diagnostics.count(),
+            1
+        );
+        assert_eq!(
+            word_references("ahead", ".word 1f\n").0.diagnostics.count(),
+            1
+        );
+        let text = ".word 3f\n3:\n.word 3f\n";
+        assert_eq!(word_references("last", text).0.diagnostics.count(), 1);
+    }
+
+    #[test]
+    fn anonymous_labels_can_precede_macro_calls() {
+        let text = ".macro twice x\nadd x, x\nadd x, x\n.endm\n1: twice r0\n1: twice r1\n.word 1b, 2f\n2:\n";
+        let (job, names) = word_references("macro", text);
+        assert_eq!(job.diagnostics.count(), 0);
+        assert_eq!(names, ["1@1", "2@0"]);
+        assert_eq!(address_of(&job, "1@0"), Some(0));
+        assert_eq!(address_of(&job, "1@1"), Some(4));
+        assert_eq!(address_of(&job, "2@0"), Some(12));
+    }
+
+    #[test]
+    fn command_line_files_already_included_are_skipped() {
+        let files: [(&str, &[u8]); 2] = [
+            ("main.S", b".include \"shared.S\"\n"),
+            ("shared.S", b"shared:\n"),
+        ];
+        let dir = write_files("skip", &files);
+        let mut job = Job::new();
+        for file in ["main.S", "shared.S", "main.S"] {
+            job.add_file(dir.join(file).to_string_lossy().into_owned());
+        }
+        job.tokenize();
+        let _ = fs::remove_dir_all(&dir);
+        assert_eq!(job.diagnostics.count(), 0);
+        assert!(job.symbols.get("shared").is_some());
+    }
+
+    #[test]
+    fn errors_name_local_labels_as_written() {
+        let text = "main:\n.equ N, .missing\nldi .here, 1\n.here:\n.here:\n";
+        let job = assemble("written", text);
+        let expected = [
+            "cannot find symbol `.missing`",
+            "expected a register, found the symbol `.here`",
+            "symbol `.here` is defined multiple times",
+        ];
+        assert_eq!(job.diagnostics.messages(), expected);
+    }
+
+    #[test]
+    fn the_last_word_of_memory_can_be_emitted() {
+        let job = assemble("last", ".addr 0xFFFE\n.short 1\nend:\n");
+        assert_eq!(job.diagnostics.count(), 0);
+        assert_eq!(address_of(&job, "end"), Some(0x10000));
+        let job = assemble("past", ".addr 0xFFFE\n.short 1\n.byte 2\n");
+        assert_eq!(job.diagnostics.count(), 1);
+        let job = assemble("straddle", ".addr 0xFFFE\nnop\nnop\n");
+        assert_eq!(job.diagnostics.count(), 1);
+
+        let mut exec = Executable::new();
+        exec.set_address(0xFFFE);
+        exec.push_short(0x0201);
+        assert_eq!(exec.size(), 0x10000);
+        assert_eq!(exec.segments(), vec![(0xFFFE, &[0x01, 0x02][..])]);
     }
-    critical!("Failed to create file path from string `{}`.", file);
 }
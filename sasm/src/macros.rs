@@ -0,0 +1,259 @@
+use crate::diagnostic::Diagnostics;
+use crate::lexer::{Lexeme, LexemeKind};
+use crate::parser;
+use crate::source::Spanned;
+use std::collections::HashSet;
+
+/// How deeply macro invocations may nest before expansion stops.
+pub const RECURSION_LIMIT: usize = 64;
+
+pub struct Macro {
+    pub name: Spanned<String>,
+    pub params: Vec<Spanned<String>>,
+    /// The lines between `.macro` and `.endm`.
+    body: Vec<Vec<Lexeme>>,
+    /// The labels defined in the body.
+    labels: HashSet<String>,
+}
+
+impl Macro {
+    pub fn new(
+        name: Spanned<String>,
+        params: Vec<Spanned<String>>,
+        body: Vec<Vec<Lexeme>>,
+    ) -> Self {
+        let mut labels = HashSet::new();
+        for line in body.iter() {
+            if let Some(label) = parser::label(line) {
+                labels.insert(label);
+            }
+        }
+        return Self {
+            name,
+            params,
+            body,
+            labels,
+        };
+    }
+
+    /// Returns the body with every parameter replaced by its argument. The
+    /// labels defined in the body get the suffix `@id`, so that every
+    /// expansion has its own.
+    pub fn expand(&self, args: &[Vec<Lexeme>], id: usize) -> Vec<Vec<Lexeme>> {
+        let mut lines = Vec::new();
+        for line in self.body.iter() {
+            let mut expanded = Vec::new();
+            for lexeme in line.iter() {
+                let name = match &lexeme.kind {
+                    LexemeKind::Ident(name) => name,
+                    _ => {
+                        expanded.push(lexeme.clone());
+                        continue;
+                    }
+                };
+                if let Some(i) = self.params.iter().position(|p| p.node == *name) {
+                    expanded.extend(args[i].iter().cloned());
+                } else if self.labels.contains(name) {
+                    expanded.push(Lexeme {
+                        kind: LexemeKind::Ident(format!("{}@{}", name, id)),
+                        span: lexeme.span,
+                    });
+                } else {
+                    expanded.push(lexeme.clone());
+                }
+            }
+            lines.push(expanded);
+        }
+        return lines;
+    }
+}
+
+/// Whether `line` starts with the directive `name`.
+pub fn starts_with(line: &[Lexeme], name: &str) -> bool {
+    return matches!(&line[0].kind, LexemeKind::Ident(ident) if ident == name);
+}
+
+/// Parses `.macro name param, param...`. The commas between parameters
+/// are optional.
+pub fn parse_header(
+    line: &[Lexeme],
+    diags: &mut Diagnostics,
+) -> Option<(Spanned<String>, Vec<Spanned<String>>)> {
+    let mut names = Vec::new();
+    for lexeme in line[1..].iter() {
+        match &lexeme.kind {
+            LexemeKind::Ident(name) => names.push(Spanned::new(name.clone(), lexeme.span)),
+            LexemeKind::Comma if !names.is_empty() => {}
+            LexemeKind::Newline => break,
+            other => {
+                let expected = if names.is_empty() {
+                    "a macro name"
+                } else {
+                    "a parameter name"
+                };
+                let message = format!("expected {}, found {}", expected, other.describe());
+                diags.error(lexeme.span, message);
+                return None;
+            }
+        }
+    }
+    if names.is_empty() {
+        let lexeme = &line[line.len() - 1];
+        let message = format!("expected a macro name, found {}", lexeme.kind.describe());
+        diags.error(lexeme.span, message);
+        return None;
+    }
+    let name = names.remove(0);
+    return Some((name, names));
+}
+
+/// Returns how many of `lines` make up the body of a macro, up to the
+/// `.endm` closing it. Macros defined inside the body are skipped over.
+pub fn body_len(lines: &[Vec<Lexeme>]) -> Option<usize> {
+    let mut depth = 0;
+    for (i, line) in lines.iter().enumerate() {
+        if starts_with(line, ".macro") {
+            depth += 1;
+        } else if starts_with(line, ".endm") {
+            if depth == 0 {
+                return Some(i);
+            }
+            depth -= 1;
+        }
+    }
+    return None;
+}
+
+/// Splits the arguments of an invocation on the commas outside of
+/// parentheses.
+pub fn split_args(lexemes: &[Lexeme]) -> Vec<Vec<Lexeme>> {
+    let mut args = Vec::new();
+    if lexemes.is_empty() {
+        return args;
+    }
+    let mut arg = Vec::new();
+    let mut depth = 0;
+    for lexeme in lexemes.iter() {
+        match lexeme.kind {
+            LexemeKind::Comma if depth == 0 => {
+                args.push(arg);
+                arg = Vec::new();
+                continue;
+            }
+            LexemeKind::Punct("(") => depth += 1,
+            LexemeKind::Punct(")") => depth -= 1,
+            _ => {}
+        }
+        arg.push(lexeme.clone());
+    }
+    args.push(arg);
+    return args;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::tokenize;
+    use crate::source::SourceMap;
+
+    fn lex(text: &str) -> Vec<Vec<Lexeme>> {
+        let mut sources = SourceMap::default();
+        let file = sources.add("test.S".to_string(), text.to_string());
+        let mut diags = Diagnostics::default();
+        let lines = tokenize(file, sources.get(file), &mut diags);
+        assert_eq!(diags.count(), 0);
+        return lines;
+    }
+
+    fn kinds(lines: &[Vec<Lexeme>]) -> Vec<Vec<LexemeKind>> {
+        return lines
+            .iter()
+            .map(|line| line.iter().map(|l| l.kind.clone()).collect())
+            .collect();
+    }
+
+    /// Splits the operands of `text` and returns the lexemes of each one.
+    fn split(text: &str) -> Vec<Vec<LexemeKind>> {
+        let line = &lex(&format!("m {}", text))[0];
+        return kinds(&split_args(&line[1..line.len() - 1]));
+    }
+
+    fn ident(name: &str) -> LexemeKind {
+        return LexemeKind::Ident(name.to_string());
+    }
+
+    #[test]
+    fn splits_arguments_on_top_level_commas() {
+        assert_eq!(
+            split("r0, (1, 2), 3"),
+            vec![
+                vec![ident("r0")],
+                vec![
+                    LexemeKind::Punct("("),
+                    LexemeKind::Int(1),
+                    LexemeKind::Comma,
+                    LexemeKind::Int(2),
+                    LexemeKind::Punct(")"),
+                ],
+                vec![LexemeKind::Int(3)],
+            ]
+        );
+    }
+
+    #[test]
+    fn empty_arguments_are_kept() {
+        assert_eq!(split(""), Vec::<Vec<LexemeKind>>::new());
+        assert_eq!(split(", 1,").len(), 3);
+        assert!(split(", 1,")[0].is_empty());
+    }
+
+    #[test]
+    fn body_ends_at_the_matching_endm() {
+        let lines = lex("nop\n.macro inner\nhlt\n.endm\nnop\n.endm\nafter\n");
+        assert_eq!(body_len(&lines), Some(5));
+        assert_eq!(body_len(&lines[..5]), None);
+        assert_eq!(body_len(&lex(".endm\n")), Some(0));
+    }
+
+    #[test]
+    fn header_commas_are_optional() {
+        let mut diags = Diagnostics::default();
+        let (name, params) =
+            parse_header(&lex(".macro m a, b c")[0], &mut diags).expect("a header");
+        assert_eq!(name.node, "m");
+        let params: Vec<&str> = params.iter().map(|p| p.node.as_str()).collect();
+        assert_eq!(params, vec!["a", "b", "c"]);
+        assert!(parse_header(&lex(".macro")[0], &mut diags).is_none());
+        assert!(parse_header(&lex(".macro , a")[0], &mut diags).is_none());
+        assert_eq!(diags.count(), 2);
+    }
+
+    #[test]
+    fn expansion_substitutes_arguments_and_renames_labels() {
+        let mut diags = Diagnostics::default();
+        let lines =
+            lex(".macro m dst, value\nagain: ldi dst, value\njmp again\n.endm\nm r1, 2 + 3");
+        let (name, params) = parse_header(&lines[0], &mut diags).expect("a header");
+        let body = lines[1..3].to_vec();
+        let invocation = &lines[4];
+        let args = split_args(&invocation[1..invocation.len() - 1]);
+        let expanded = Macro::new(name, params, body).expand(&args, 7);
+        assert_eq!(
+            kinds(&expanded),
+            vec![
+                vec![
+                    ident("again@7"),
+                    LexemeKind::Colon,
+                    ident("ldi"),
+                    ident("r1"),
+                    LexemeKind::Comma,
+                    LexemeKind::Int(2),
+                    LexemeKind::Punct("+"),
+                    LexemeKind::Int(3),
+                    LexemeKind::Newline,
+                ],
+                vec![ident("jmp"), ident("again@7"), LexemeKind::Newline],
+            ]
+        );
+    }
+}
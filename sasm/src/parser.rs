@@ -0,0 +1,373 @@
+use crate::diagnostic::Diagnostics;
+use crate::expr::{BinaryOp, Expr, Function, UnaryOp};
+use crate::lexer::{Lexeme, LexemeKind};
+use crate::source::{Span, Spanned};
+use crate::RegisterId;
+
+pub enum Operand {
+    Register(u16),
+    Expr(Expr),
+    Str(Vec<u8>),
+}
+
+impl Operand {
+    /// How the operand is named in error messages.
+    pub fn describe(&self) -> String {
+        match self {
+            Operand::Register(_) => return "a register".to_string(),
+            Operand::Str(_) => return "a string".to_string(),
+            Operand::Expr(Expr::Number(n)) => return format!("the number `{}`", n),
+            Operand::Expr(Expr::Symbol(name)) => return format!("the symbol `{}`", name),
+            Operand::Expr(_) => return "an expression".to_string(),
+        }
+    }
+}
+
+/// An instruction or a directive along with its operands.
+pub struct Operation {
+    pub name: Spanned<String>,
+    pub operands: Vec<Spanned<Operand>>,
+    pub span: Span,
+}
+
+/// One line of source: an optional label followed by an optional operation.
+pub struct Statement {
+    pub label: Option<Spanned<String>>,
+    pub operation: Option<Operation>,
+}
+
+struct Parser<'a> {
+    lexemes: &'a [Lexeme],
+    pos: usize,
+    diags: &'a mut Diagnostics,
+}
+
+/// Parses one line of lexemes, which must end with a `Newline`. If the line
+/// fails to parse, the error is reported and only its label is kept.
+pub fn parse(line: &[Lexeme], diags: &mut Diagnostics) -> Statement {
+    let mut parser = Parser {
+        lexemes: line,
+        pos: 0,
+        diags,
+    };
+    return parser.statement();
+}
+
+/// Returns the label `line` starts with, if any. Numbers are anonymous
+/// labels, referred to as `1b` and `1f`.
+pub fn label(line: &[Lexeme]) -> Option<String> {
+    if line[1].kind != LexemeKind::Colon {
+        return None;
+    }
+    match &line[0].kind {
+        LexemeKind::Ident(name) => return Some(name.clone()),
+        LexemeKind::Int(n) => return Some(n.to_string()),
+        _ => return None,
+    }
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> &Lexeme {
+        return &self.lexemes[self.pos];
+    }
+
+    fn statement(&mut self) -> Statement {
+        let name = label(&self.lexemes[self.pos..]);
+        let label = name.map(|name| Spanned::new(name, self.peek().span));
+        if label.is_some() {
+            self.pos += 2;
+        }
+        let operation = match self.peek().kind {
+            LexemeKind::Newline => None,
+            _ => self.operation(),
+        };
+        return Statement { label, operation };
+    }
+
+    fn operation(&mut self) -> Option<Operation> {
+        let name = match &self.peek().kind {
+            LexemeKind::Ident(name) => Spanned::new(name.clone(), self.peek().span),
+            _ => return self.unexpected("an instruction or directive"),
+        };
+        self.pos += 1;
+        let mut operands = Vec::new();
+        if self.peek().kind != LexemeKind::Newline {
+            loop {
+                operands.push(self.operand()?);
+                match self.peek().kind {
+                    LexemeKind::Comma => self.pos += 1,
+                    LexemeKind::Newline => break,
+                    _ => return self.unexpected("`,` or end of line"),
+                }
+            }
+        }
+        let span = match operands.last() {
+            Some(last) => name.span.to(last.span),
+            None => name.span,
+        };
+        return Some(Operation {
+            name,
+            operands,
+            span,
+        });
+    }
+
+    fn operand(&mut self) -> Option<Spanned<Operand>> {
+        let lexeme = self.peek();
+        let operand = match &lexeme.kind {
+            LexemeKind::Ident(name) => register_id(name).map(Operand::Register),
+            LexemeKind::Str(value) => Some(Operand::Str(value.clone())),
+            _ => None,
+        };
+        if let Some(operand) = operand {
+            let span = lexeme.span;
+            self.pos += 1;
+            return Some(Spanned::new(operand, span));
+        }
+        let expr = self.expression(0)?;
+        return Some(Spanned::new(Operand::Expr(expr.node), expr.span));
+    }
+
+    /// Parses binary operations binding at least as tightly as `min_power`.
+    fn expression(&mut self, min_power: u8) -> Option<Spanned<Expr>> {
+        let mut left = self.unary()?;
+        while let Some((op, power)) = self.binary_op() {
+            if power < min_power {
+                break;
+            }
+            self.pos += 1;
+            let right = self.expression(power + 1)?;
+            let span = left.span.to(right.span);
+            left = Spanned::new(Expr::Binary(op, Box::new(left), Box::new(right)), span);
+        }
+        return Some(left);
+    }
+
+    /// The binary operator at the current position, if any.
+    fn binary_op(&self) -> Option<(BinaryOp, u8)> {
+        match &self.peek().kind {
+            LexemeKind::Punct(punct) => return BinaryOp::from_punct(punct),
+            _ => return None,
+        }
+    }
+
+    fn unary(&mut self) -> Option<Spanned<Expr>> {
+        let Lexeme { kind, span } = self.peek().clone();
+        let op = match kind {
+            LexemeKind::Punct("-") => UnaryOp::Neg,
+            LexemeKind::Punct("~") => UnaryOp::Not,
+            LexemeKind::Punct("!") => UnaryOp::LogicalNot,
+            LexemeKind::Punct("+") => {
+                self.pos += 1;
+                let operand = self.unary()?;
+                return Some(Spanned::new(operand.node, span.to(operand.span)));
+            }
+            _ => return self.primary(),
+        };
+        self.pos += 1;
+        let operand = self.unary()?;
+        let span = span.to(operand.span);
+        return Some(Spanned::new(Expr::Unary(op, Box::new(operand)), span));
+    }
+
+    fn primary(&mut self) -> Option<Spanned<Expr>> {
+        let Lexeme { kind, span } = self.peek().clone();
+        match kind {
+            LexemeKind::Int(n) => {
+                self.pos += 1;
+                return Some(Spanned::new(Expr::Number(n), span));
+            }
+            LexemeKind::Punct("(") => {
+                self.pos += 1;
+                let inner = self.expression(0)?;
+                let close = self.expect(")")?;
+                return Some(Spanned::new(inner.node, span.to(close)));
+            }
+            LexemeKind::Ident(name) => {
+                self.pos += 1;
+                if self.peek().kind != LexemeKind::Punct("(") {
+                    return Some(Spanned::new(Expr::Symbol(name), span));
+                }
+                let function = match Function::from_name(&name) {
+                    Some(function) => function,
+                    None => {
+                        let message = format!("unknown function `{}`", name);
+                        self.diags.error(span, message);
+                        return None;
+                    }
+                };
+                self.pos += 1;
+                let argument = self.expression(0)?;
+                let close = self.expect(")")?;
+                let call = Expr::Call(function, Box::new(argument));
+                return Some(Spanned::new(call, span.to(close)));
+            }
+            _ => return self.unexpected("an expression"),
+        }
+    }
+
+    /// Consumes the punctuation `punct`, returning its span.
+    fn expect(&mut self, punct: &'static str) -> Option<Span> {
+        let lexeme = self.peek();
+        if lexeme.kind == LexemeKind::Punct(punct) {
+            let span = lexeme.span;
+            self.pos += 1;
+            return Some(span);
+        }
+        return self.unexpected(&format!("`{}`", punct));
+    }
+
+    fn unexpected<T>(&mut self, expected: &str) -> Option<T> {
+        let lexeme = self.peek();
+        let found = lexeme.kind.describe();
+        let span = lexeme.span;
+        self.diags
+            .error(span, format!("expected {}, found {}", expected, found));
+        return None;
+    }
+}
+
+/// Maps `sp`, `r<n>` and `c<n>` to their register number.
+fn register_id(name: &str) -> Option<u16> {
+    if name == "sp" {
+        return Some(RegisterId::SP as u16);
+    }
+    let (first, digits) = name.split_at(1);
+    if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let num = digits.parse::<u16>().ok()?;
+    match first {
+        "r" => return Some(num),
+        "c" => return num.checked_add(RegisterId::C0 as u16),
+        _ => return None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::tokenize;
+    use crate::source::SourceMap;
+
+    fn parse_text(text: &str) -> (Vec<Statement>, usize) {
+        let mut sources = SourceMap::default();
+        let file = sources.add("test.S".to_string(), text.to_string());
+        let mut diags = Diagnostics::default();
+        let lines = tokenize(file, sources.get(file), &mut diags);
+        let statements = lines.iter().map(|line| parse(line, &mut diags)).collect();
+        return (statements, diags.count());
+    }
+
+    #[test]
+    fn parses_labels_and_operands() {
+        let (statements, errors) = parse_text("loop: add r1, c0\n\nldi r0, end\n");
+        assert_eq!(errors, 0);
+        assert_eq!(statements.len(), 2);
+        assert_eq!(
+            statements[0].label.as_ref().map(|l| l.node.as_str()),
+            Some("loop")
+        );
+        let operation = statements[0].operation.as_ref().expect("an operation");
+        assert_eq!(operation.name.node, "add");
+        let operands: Vec<String> = operation
+            .operands
+            .iter()
+            .map(|o| o.node.describe())
+            .collect();
+        assert_eq!(operands, vec!["a register", "a register"]);
+        let operation = statements[1].operation.as_ref().expect("an operation");
+        assert!(
+            matches!(operation.operands[1].node, Operand::Expr(Expr::Symbol(ref name)) if name == "end")
+        );
+    }
+
+    #[test]
+    fn lines_that_fail_to_parse_keep_their_label() {
+        let (statements, errors) = parse_text("here: add r1 r2\nhlt\n");
+        assert_eq!(errors, 1);
+        assert_eq!(statements.len(), 2);
+        assert!(statements[0].label.is_some());
+        assert!(statements[0].operation.is_none());
+        assert!(statements[1].operation.is_some());
+    }
+
+    #[test]
+    fn names_registers() {
+        assert_eq!(register_id("r7"), Some(7));
+        assert_eq!(register_id("sp"), Some(RegisterId::SP as u16));
+        assert_eq!(register_id("c1"), Some(RegisterId::C0 as u16 + 1));
+        assert_eq!(register_id("r"), None);
+        assert_eq!(register_id("x1"), None);
+        assert_eq!(register_id("r1a"), None);
+    }
+
+    /// Parses the only operand of `text` and prints it fully parenthesized.
+    fn parse_operand(text: &str) -> String {
+        let (mut statements, errors) = parse_text(&format!(".short {}", text));
+        assert_eq!(errors, 0);
+        let mut operands = statements
+            .remove(0)
+            .operation
+            .expect("an operation")
+            .operands;
+        assert_eq!(operands.len(), 1);
+        match operands.remove(0).node {
+            Operand::Expr(expr) => return render(&expr),
+            operand => panic!("expected an expression, found {}", operand.describe()),
+        }
+    }
+
+    fn render(expr: &Expr) -> String {
+        match expr {
+            Expr::Number(n) => return n.to_string(),
+            Expr::Symbol(name) => return name.clone(),
+            Expr::Unary(op, operand) => return format!("({:?} {})", op, render(&operand.node)),
+            Expr::Binary(op, left, right) => {
+                let (left, right) = (render(&left.node), render(&right.node));
+                return format!("({:?} {} {})", op, left, right);
+            }
+            Expr::Call(function, argument) => {
+                return format!("({:?} {})", function, render(&argument.node));
+            }
+        }
+    }
+
+    #[test]
+    fn multiplication_binds_tighter_than_addition() {
+        assert_eq!(parse_operand("1 + 2 * 3"), "(Add 1 (Mul 2 3))");
+        assert_eq!(parse_operand("(1 + 2) * 3"), "(Mul (Add 1 2) 3)");
+    }
+
+    #[test]
+    fn binary_operators_are_left_associative() {
+        assert_eq!(parse_operand("8 - 4 - 2"), "(Sub (Sub 8 4) 2)");
+        assert_eq!(parse_operand("a / b % c"), "(Rem (Div a b) c)");
+    }
+
+    #[test]
+    fn bitwise_and_logical_operators_follow_c_precedence() {
+        assert_eq!(parse_operand("a | b & c"), "(Or a (And b c))");
+        assert_eq!(parse_operand("a ^ b | c"), "(Or (Xor a b) c)");
+        assert_eq!(parse_operand("1 << 2 + 3"), "(Shl 1 (Add 2 3))");
+        assert_eq!(parse_operand("a == b & c"), "(And (Eq a b) c)");
+        assert_eq!(parse_operand("a < b == c"), "(Eq (Lt a b) c)");
+        assert_eq!(
+            parse_operand("a || b && c"),
+            "(LogicalOr a (LogicalAnd b c))"
+        );
+    }
+
+    #[test]
+    fn unary_operators_bind_tightest() {
+        assert_eq!(parse_operand("-a * ~b"), "(Mul (Neg a) (Not b))");
+        assert_eq!(parse_operand("!a && b"), "(LogicalAnd (LogicalNot a) b)");
+        assert_eq!(parse_operand("hi(a + 1)"), "(Hi (Add a 1))");
+    }
+
+    #[test]
+    fn unbalanced_parentheses_are_reported() {
+        assert_eq!(parse_text(".short (1 + 2").1, 1);
+        assert_eq!(parse_text(".short foo(1)").1, 1);
+    }
+}
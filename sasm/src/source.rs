@@ -0,0 +1,109 @@
+/// A byte range in one of the source files.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Span {
+    pub file: usize,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(file: usize, start: usize, end: usize) -> Self {
+        return Self { file, start, end };
+    }
+
+    /// The span covering both `self` and `other`, or just `self` if they are
+    /// in different files.
+    pub fn to(self, other: Span) -> Span {
+        if self.file != other.file {
+            return self;
+        }
+        return Span::new(
+            self.file,
+            self.start.min(other.start),
+            self.end.max(other.end),
+        );
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Spanned<T> {
+    pub node: T,
+    pub span: Span,
+}
+
+impl<T> Spanned<T> {
+    pub fn new(node: T, span: Span) -> Self {
+        return Self { node, span };
+    }
+}
+
+pub struct SourceFile {
+    pub name: String,
+    pub text: String,
+    line_starts: Vec<usize>,
+}
+
+impl SourceFile {
+    fn new(name: String, text: String) -> Self {
+        let mut line_starts = vec![0];
+        for (i, b) in text.bytes().enumerate() {
+            if b == b'\n' {
+                line_starts.push(i + 1);
+            }
+        }
+        return Self {
+            name,
+            text,
+            line_starts,
+        };
+    }
+
+    /// Returns the 1-based line and column of the byte at `offset`.
+    pub fn position(&self, offset: usize) -> (usize, usize) {
+        let line = match self.line_starts.binary_search(&offset) {
+            Ok(line) => line,
+            Err(next) => next - 1,
+        };
+        let start = self.line_starts[line];
+        let column = self.text[start..offset.min(self.text.len())]
+            .chars()
+            .count();
+        return (line + 1, column + 1);
+    }
+
+    /// Returns the text of the 1-based `line`, without its line break.
+    pub fn line(&self, line: usize) -> &str {
+        let start = self.line_starts[line - 1];
+        let end = match self.line_starts.get(line) {
+            Some(next) => next - 1,
+            None => self.text.len(),
+        };
+        return self.text[start..end].trim_end_matches('\r');
+    }
+}
+
+/// Every file read during assembly, indexed by the `file` of a `Span`.
+#[derive(Default)]
+pub struct SourceMap {
+    files: Vec<SourceFile>,
+}
+
+impl SourceMap {
+    pub fn add(&mut self, name: String, text: String) -> usize {
+        self.files.push(SourceFile::new(name, text));
+        return self.files.len() - 1;
+    }
+
+    pub fn get(&self, file: usize) -> &SourceFile {
+        return &self.files[file];
+    }
+
+    /// Returns the text `span` covers.
+    pub fn text(&self, span: Span) -> &str {
+        let text = match self.files.get(span.file) {
+            Some(file) => &file.text,
+            None => return "",
+        };
+        return text.get(span.start..span.end).unwrap_or("");
+    }
+}
@@ -0,0 +1,110 @@
+use crate::source::Span;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SymbolKind {
+    /// An address defined by `name:`.
+    Label,
+    /// A value defined by `.equ`.
+    Constant,
+    /// A value defined by `.set`, which may be redefined.
+    Variable,
+}
+
+pub struct Symbol {
+    pub kind: SymbolKind,
+    pub value: i64,
+    /// Where the symbol was defined.
+    pub span: Span,
+}
+
+/// Every label and constant defined in the sources, keyed by name.
+#[derive(Default)]
+pub struct SymbolTable {
+    symbols: HashMap<String, Symbol>,
+}
+
+impl SymbolTable {
+    /// Defines `name`. A variable may be redefined by another variable;
+    /// any other symbol that already exists is left untouched and the span
+    /// of its definition is returned.
+    pub fn define(&mut self, name: &str, symbol: Symbol) -> Result<(), Span> {
+        match self.symbols.entry(name.to_string()) {
+            Entry::Occupied(mut entry) => {
+                let variable = SymbolKind::Variable;
+                if entry.get().kind == variable && symbol.kind == variable {
+                    entry.insert(symbol);
+                    return Ok(());
+                }
+                return Err(entry.get().span);
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(symbol);
+                return Ok(());
+            }
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Symbol> {
+        return self.symbols.get(name);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn symbol(kind: SymbolKind, value: i64, start: usize) -> Symbol {
+        return Symbol {
+            kind,
+            value,
+            span: Span::new(0, start, start + 1),
+        };
+    }
+
+    #[test]
+    fn names_are_compared_whole() {
+        // These collided when labels were identified by XORing their bytes
+        // eight at a time.
+        let mut symbols = SymbolTable::default();
+        let label = |value, start| symbol(SymbolKind::Label, value, start);
+        assert!(symbols.define("loop_onecount_to", label(1, 0)).is_ok());
+        assert!(symbols.define("count_toloop_one", label(2, 1)).is_ok());
+        assert_eq!(symbols.get("loop_onecount_to").map(|s| s.value), Some(1));
+        assert_eq!(symbols.get("count_toloop_one").map(|s| s.value), Some(2));
+        assert!(symbols.get("loop_one").is_none());
+    }
+
+    #[test]
+    fn variables_may_be_redefined() {
+        let mut symbols = SymbolTable::default();
+        assert!(symbols
+            .define("n", symbol(SymbolKind::Variable, 1, 0))
+            .is_ok());
+        assert!(symbols
+            .define("n", symbol(SymbolKind::Variable, 2, 1))
+            .is_ok());
+        assert_eq!(symbols.get("n").map(|s| s.value), Some(2));
+    }
+
+    #[test]
+    fn other_symbols_keep_their_first_definition() {
+        let mut symbols = SymbolTable::default();
+        assert!(symbols
+            .define("a", symbol(SymbolKind::Constant, 1, 0))
+            .is_ok());
+        let again = symbols.define("a", symbol(SymbolKind::Constant, 2, 5));
+        assert_eq!(again, Err(Span::new(0, 0, 1)));
+        let variable = symbols.define("a", symbol(SymbolKind::Variable, 3, 5));
+        assert_eq!(variable, Err(Span::new(0, 0, 1)));
+        assert!(symbols
+            .define("v", symbol(SymbolKind::Variable, 1, 2))
+            .is_ok());
+        assert!(symbols
+            .define("v", symbol(SymbolKind::Label, 4, 6))
+            .is_err());
+        assert_eq!(symbols.get("a").map(|s| s.value), Some(1));
+        assert_eq!(symbols.get("v").map(|s| s.value), Some(1));
+    }
+}
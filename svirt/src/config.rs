@@ -0,0 +1,473 @@
+use crate::devices::disk::{self, DiskConfig};
+use crate::devices::dma::{self, DmaConfig};
+use crate::devices::framebuffer::{self, FramebufferConfig, MAX_SIZE};
+use crate::devices::keyboard::{self, KeyboardConfig};
+use crate::devices::rng::{self, RngConfig};
+use crate::devices::rtc::{self, RtcConfig};
+use crate::image::ImageFormat;
+use crate::Configuration;
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    #[serde(default)]
+    machine: MachineSection,
+    #[serde(default)]
+    firmware: FirmwareSection,
+    #[serde(default)]
+    devices: DevicesSection,
+    #[serde(default)]
+    semihosting: SemihostingSection,
+    #[serde(default)]
+    remote: RemoteSection,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct MachineSection {
+    memory_size: Option<u16>,
+    initial_pc: Option<u16>,
+    cycles_per_second: Option<u64>,
+    verbose: Option<bool>,
+    core_file: Option<String>,
+    trace_length: Option<usize>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct FirmwareSection {
+    file: Option<String>,
+    format: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct SemihostingSection {
+    enabled: Option<bool>,
+    args: Option<Vec<String>>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct RemoteSection {
+    listen: Option<String>,
+    paused: Option<bool>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct DevicesSection {
+    framebuffer: Option<FramebufferSection>,
+    keyboard: Option<KeyboardSection>,
+    disk: Option<DiskSection>,
+    rtc: Option<RtcSection>,
+    rng: Option<RngSection>,
+    dma: Option<DmaSection>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FramebufferSection {
+    base: Option<u16>,
+    width: Option<u16>,
+    height: Option<u16>,
+    output: Option<String>,
+    interval: Option<u64>,
+    terminal: Option<bool>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct KeyboardSection {
+    base: Option<u16>,
+    fifo_size: Option<usize>,
+    raw: Option<bool>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DiskSection {
+    base: Option<u16>,
+    image: Option<String>,
+    read_only: Option<bool>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RtcSection {
+    base: Option<u16>,
+    epoch: Option<u64>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RngSection {
+    base: Option<u16>,
+    seed: Option<u64>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DmaSection {
+    base: Option<u16>,
+    cycles_per_word: Option<u64>,
+}
+
+impl Configuration {
+    /// Applies the settings found in the TOML file at `path` on top of the
+    /// current configuration. Relative firmware and disk image paths are
+    /// resolved against the directory containing the configuration file.
+    pub fn load_file(&mut self, path: &str) -> Result<(), String> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) => return Err(format!("cannot read `{}`: {}", path, e)),
+        };
+        let file: ConfigFile = match toml::from_str(&text) {
+            Ok(file) => file,
+            Err(e) => return Err(format!("invalid configuration `{}`: {}", path, e.message())),
+        };
+
+        let machine = file.machine;
+        if let Some(size) = machine.memory_size {
+            self.memory_size = size;
+        }
+        if let Some(pc) = machine.initial_pc {
+            self.initial_pc = pc;
+        }
+        if let Some(cps) = machine.cycles_per_second {
+            self.cycles_per_second = cps as u128;
+        }
+        if let Some(verbose) = machine.verbose {
+            self.verbose = verbose;
+        }
+        if let Some(core_file) = machine.core_file {
+            // An empty name turns core files off again.
+            self.core_file = if core_file.is_empty() {
+                None
+            } else {
+                Some(PathBuf::from(core_file))
+            };
+        }
+        if let Some(len) = machine.trace_length {
+            self.trace_length = len;
+        }
+
+        let firmware = file.firmware;
+        if let Some(name) = firmware.format {
+            match ImageFormat::from_name(&name) {
+                Some(format) => self.firmware_format = Some(format),
+                None => return Err(format!("unknown firmware format `{}`", name)),
+            }
+        }
+        let base = Path::new(path).parent().unwrap_or(Path::new(""));
+        if let Some(file) = firmware.file {
+            self.firmware_file = base.join(file).to_string_lossy().to_string();
+        }
+
+        if let Some(enabled) = file.semihosting.enabled {
+            self.semihosting = enabled;
+        }
+        if let Some(args) = file.semihosting.args {
+            self.args = args;
+        }
+
+        if let Some(listen) = file.remote.listen {
+            self.listen = Some(listen);
+        }
+        if let Some(paused) = file.remote.paused {
+            self.paused = paused;
+        }
+
+        if let Some(section) = file.devices.framebuffer {
+            let fb = self
+                .framebuffer
+                .get_or_insert_with(FramebufferConfig::default);
+            fb.base = section.base.unwrap_or(fb.base);
+            fb.width = section.width.unwrap_or(fb.width);
+            fb.height = section.height.unwrap_or(fb.height);
+            fb.output = section.output.unwrap_or(fb.output.clone());
+            fb.interval = section.interval.unwrap_or(fb.interval);
+            fb.terminal = section.terminal.unwrap_or(fb.terminal);
+        }
+        if let Some(section) = file.devices.keyboard {
+            let kbd = self.keyboard.get_or_insert_with(KeyboardConfig::default);
+            kbd.base = section.base.unwrap_or(kbd.base);
+            kbd.fifo_size = section.fifo_size.unwrap_or(kbd.fifo_size);
+            kbd.raw = section.raw.unwrap_or(kbd.raw);
+        }
+        if let Some(section) = file.devices.disk {
+            let disk = self.disk.get_or_insert_with(DiskConfig::default);
+            disk.base = section.base.unwrap_or(disk.base);
+            if let Some(image) = section.image {
+                disk.image = base.join(image).to_string_lossy().to_string();
+            }
+            disk.read_only = section.read_only.unwrap_or(disk.read_only);
+        }
+        if let Some(section) = file.devices.rtc {
+            let rtc = self.rtc.get_or_insert_with(RtcConfig::default);
+            rtc.base = section.base.unwrap_or(rtc.base);
+            rtc.epoch = section.epoch.or(rtc.epoch);
+        }
+        if let Some(section) = file.devices.rng {
+            let rng = self.rng.get_or_insert_with(RngConfig::default);
+            rng.base = section.base.unwrap_or(rng.base);
+            rng.seed = section.seed.or(rng.seed);
+        }
+        if let Some(section) = file.devices.dma {
+            let dma = self.dma.get_or_insert_with(DmaConfig::default);
+            dma.base = section.base.unwrap_or(dma.base);
+            dma.cycles_per_word = section.cycles_per_word.unwrap_or(dma.cycles_per_word);
+        }
+        return Ok(());
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.memory_size == 0 {
+            return Err("memory size cannot be 0".to_string());
+        }
+        if self.cycles_per_second == 0 {
+            return Err("cycles per second cannot be 0".to_string());
+        }
+        if !self.initial_pc.is_multiple_of(2) {
+            return Err(format!(
+                "initial PC {:0>4X} is not 2 byte aligned",
+                self.initial_pc
+            ));
+        }
+        if let Some(listen) = &self.listen {
+            if !listen.starts_with("unix:") && !listen.starts_with("tcp:") {
+                return Err(format!(
+                    "invalid listen address `{}`, expected unix:PATH or tcp:PORT",
+                    listen
+                ));
+            }
+        }
+        if self.record.is_some() && self.replay.is_some() {
+            return Err("cannot record and replay at the same time".to_string());
+        }
+        if self.paused && self.listen.is_none() {
+            return Err("starting paused requires a listen address".to_string());
+        }
+        if let Some(fb) = &self.framebuffer {
+            if fb.width == 0 || fb.height == 0 {
+                return Err("framebuffer size cannot be 0".to_string());
+            }
+            if fb.width > MAX_SIZE || fb.height > MAX_SIZE {
+                return Err(format!(
+                    "framebuffer cannot be larger than {}x{} pixels",
+                    MAX_SIZE, MAX_SIZE
+                ));
+            }
+            if !fb.width.is_multiple_of(8) {
+                return Err("framebuffer width must be a multiple of 8".to_string());
+            }
+        }
+        if let Some(kbd) = &self.keyboard {
+            if kbd.fifo_size == 0 {
+                return Err("keyboard FIFO size cannot be 0".to_string());
+            }
+        }
+        if let Some(disk) = &self.disk {
+            if disk.image.is_empty() {
+                return Err("disk requires an image file".to_string());
+            }
+        }
+        if let Some(dma) = &self.dma {
+            if dma.cycles_per_word == 0 {
+                return Err("DMA cycles per word cannot be 0".to_string());
+            }
+        }
+        return self.validate_windows();
+    }
+
+    /// Checks that the register windows of the devices fit in the address
+    /// space and that no two of them share an address.
+    fn validate_windows(&self) -> Result<(), String> {
+        let windows = [
+            (
+                "framebuffer",
+                self.framebuffer.as_ref().map(|d| d.base),
+                framebuffer::WINDOW_SIZE,
+            ),
+            (
+                "keyboard",
+                self.keyboard.as_ref().map(|d| d.base),
+                keyboard::WINDOW_SIZE,
+            ),
+            (
+                "disk",
+                self.disk.as_ref().map(|d| d.base),
+                disk::WINDOW_SIZE,
+            ),
+            ("RTC", self.rtc.as_ref().map(|d| d.base), rtc::WINDOW_SIZE),
+            ("RNG", self.rng.as_ref().map(|d| d.base), rng::WINDOW_SIZE),
+            ("DMA", self.dma.as_ref().map(|d| d.base), dma::WINDOW_SIZE),
+        ];
+        let mut mapped: Vec<(&str, u32, u32)> = Vec::new();
+        for (name, base, size) in windows {
+            let base = match base {
+                Some(base) => base as u32,
+                None => continue,
+            };
+            let end = base + size as u32;
+            if end > 0x10000 {
+                return Err(format!(
+                    "{} registers at {:0>4X} extend past FFFF",
+                    name, base
+                ));
+            }
+            for (other, other_base, other_end) in mapped.iter() {
+                if base < *other_end && *other_base < end {
+                    return Err(format!(
+                        "{} registers at {:0>4X} overlap the {} registers at {:0>4X}",
+                        name, base, other, other_base
+                    ));
+                }
+            }
+            mapped.push((name, base, end));
+        }
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Applies `change` to the default configuration and returns why it
+    /// does not validate.
+    fn rejection(change: impl FnOnce(&mut Configuration)) -> String {
+        let mut config = Configuration::default();
+        change(&mut config);
+        return config.validate().expect_err("an invalid configuration");
+    }
+
+    #[test]
+    fn the_default_configuration_is_valid() {
+        assert_eq!(Configuration::default().validate(), Ok(()));
+    }
+
+    #[test]
+    fn rejects_invalid_machines() {
+        assert_eq!(rejection(|c| c.memory_size = 0), "memory size cannot be 0");
+        assert_eq!(
+            rejection(|c| c.cycles_per_second = 0),
+            "cycles per second cannot be 0"
+        );
+        assert_eq!(
+            rejection(|c| c.initial_pc = 0x0101),
+            "initial PC 0101 is not 2 byte aligned"
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_remote_settings() {
+        assert_eq!(
+            rejection(|c| c.listen = Some("localhost:1234".to_string())),
+            "invalid listen address `localhost:1234`, expected unix:PATH or tcp:PORT"
+        );
+        assert_eq!(
+            rejection(|c| c.paused = true),
+            "starting paused requires a listen address"
+        );
+        assert_eq!(
+            rejection(|c| {
+                c.record = Some("a.log".to_string());
+                c.replay = Some("b.log".to_string());
+            }),
+            "cannot record and replay at the same time"
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_devices() {
+        let framebuffer = |width, height| {
+            return move |c: &mut Configuration| {
+                c.framebuffer = Some(FramebufferConfig {
+                    width,
+                    height,
+                    ..FramebufferConfig::default()
+                });
+            };
+        };
+        assert_eq!(
+            rejection(framebuffer(0, 64)),
+            "framebuffer size cannot be 0"
+        );
+        assert_eq!(
+            rejection(framebuffer(128, MAX_SIZE + 1)),
+            "framebuffer cannot be larger than 1024x1024 pixels"
+        );
+        assert_eq!(
+            rejection(framebuffer(100, 64)),
+            "framebuffer width must be a multiple of 8"
+        );
+        assert_eq!(
+            rejection(|c| {
+                c.keyboard = Some(KeyboardConfig {
+                    fifo_size: 0,
+                    ..KeyboardConfig::default()
+                });
+            }),
+            "keyboard FIFO size cannot be 0"
+        );
+        assert_eq!(
+            rejection(|c| c.disk = Some(DiskConfig::default())),
+            "disk requires an image file"
+        );
+        assert_eq!(
+            rejection(|c| {
+                c.dma = Some(DmaConfig {
+                    cycles_per_word: 0,
+                    ..DmaConfig::default()
+                });
+            }),
+            "DMA cycles per word cannot be 0"
+        );
+    }
+
+    #[test]
+    fn rejects_overlapping_device_windows() {
+        let mut config = Configuration {
+            framebuffer: Some(FramebufferConfig::default()),
+            keyboard: Some(KeyboardConfig::default()),
+            rtc: Some(RtcConfig::default()),
+            rng: Some(RngConfig::default()),
+            dma: Some(DmaConfig::default()),
+            ..Configuration::default()
+        };
+        assert_eq!(config.validate(), Ok(()));
+        // The RTC window ends where the RNG one starts.
+        config.rng.as_mut().expect("an RNG").base = 0xFF36;
+        assert_eq!(
+            config.validate(),
+            Err("RNG registers at FF36 overlap the RTC registers at FF30".to_string())
+        );
+        config.rng = None;
+        config.dma.as_mut().expect("a DMA controller").base = 0xFF0A;
+        assert_eq!(
+            config.validate(),
+            Err("DMA registers at FF0A overlap the keyboard registers at FF10".to_string())
+        );
+    }
+
+    #[test]
+    fn rejects_device_windows_past_the_end_of_memory() {
+        let mut config = Configuration {
+            dma: Some(DmaConfig {
+                base: 0u16.wrapping_sub(dma::WINDOW_SIZE),
+                ..DmaConfig::default()
+            }),
+            ..Configuration::default()
+        };
+        assert_eq!(config.validate(), Ok(()));
+        config.dma.as_mut().expect("a DMA controller").base = 0xFFF8;
+        assert_eq!(
+            config.validate(),
+            Err("DMA registers at FFF8 extend past FFFF".to_string())
+        );
+    }
+}
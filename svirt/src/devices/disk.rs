@@ -0,0 +1,151 @@
+use super::{get_register, set_register, Context, Device};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+
+pub const SECTOR_SIZE: usize = 512;
+
+const REG_SECTOR: usize = 0;
+const REG_BUFFER: usize = 1;
+const REG_COMMAND: usize = 2;
+const REG_STATUS: usize = 3;
+const REG_COUNT: usize = 4;
+const REG_CTRL: usize = 5;
+const REGISTERS: usize = 6;
+pub const WINDOW_SIZE: u16 = 2 * REGISTERS as u16;
+
+const CMD_READ: u16 = 1;
+const CMD_WRITE: u16 = 2;
+const CMD_FLUSH: u16 = 3;
+
+const STATUS_DONE: u16 = 1 << 0;
+const STATUS_ERROR: u16 = 1 << 1;
+
+const CTRL_IRQ: u16 = 1 << 0;
+
+#[derive(Clone)]
+pub struct DiskConfig {
+    pub base: u16,
+    pub image: String,
+    pub read_only: bool,
+}
+
+impl Default for DiskConfig {
+    fn default() -> Self {
+        return Self {
+            base: 0xFF20,
+            image: String::new(),
+            read_only: false,
+        };
+    }
+}
+
+/// A disk controller backed by an image file on the host. Writing a command
+/// transfers one 512 byte sector between the image and main memory.
+///
+/// | Offset | Register | Description                                      |
+/// | ------ | -------- | ------------------------------------------------ |
+/// | 0x00   | SECTOR   | Sector number                                    |
+/// | 0x02   | BUFFER   | Memory address of the sector buffer              |
+/// | 0x04   | COMMAND  | 1 read, 2 write, 3 flush; starts on low byte     |
+/// | 0x06   | STATUS   | bit 0 done, bit 1 error                          |
+/// | 0x08   | COUNT    | Number of sectors in the image (read only)       |
+/// | 0x0A   | CTRL     | bit 0 raise an interrupt when a command finishes |
+pub(crate) struct Disk {
+    config: DiskConfig,
+    regs: [u16; REGISTERS],
+    file: File,
+}
+
+impl Disk {
+    pub fn open(config: DiskConfig) -> Result<Self, String> {
+        let file = match OpenOptions::new()
+            .read(true)
+            .write(!config.read_only)
+            .open(&config.image)
+        {
+            Ok(file) => file,
+            Err(e) => return Err(format!("cannot open `{}`: {}", config.image, e)),
+        };
+        let len = match file.metadata() {
+            Ok(meta) => meta.len(),
+            Err(e) => return Err(format!("cannot stat `{}`: {}", config.image, e)),
+        };
+        let sectors = len.div_ceil(SECTOR_SIZE as u64);
+        if sectors > u16::MAX as u64 {
+            return Err(format!(
+                "`{}` has more than {} sectors",
+                config.image,
+                u16::MAX
+            ));
+        }
+        let mut regs = [0; REGISTERS];
+        regs[REG_COUNT] = sectors as u16;
+        return Ok(Self { config, regs, file });
+    }
+
+    fn execute(&mut self, command: u16, ctx: &mut Context) -> bool {
+        let sector = self.regs[REG_SECTOR];
+        let buffer = self.regs[REG_BUFFER] as usize;
+        if command == CMD_FLUSH {
+            return self.file.sync_data().is_ok();
+        }
+        if sector >= self.regs[REG_COUNT] || buffer + SECTOR_SIZE > ctx.mem.data.len() {
+            return false;
+        }
+        let position = SeekFrom::Start(sector as u64 * SECTOR_SIZE as u64);
+        if self.file.seek(position).is_err() {
+            return false;
+        }
+        let data = &mut ctx.mem.data[buffer..buffer + SECTOR_SIZE];
+        match command {
+            CMD_READ => {
+                // The last sector of an image may be short; pad it with zeros.
+                data.fill(0);
+                let mut read = 0;
+                while read < SECTOR_SIZE {
+                    match self.file.read(&mut data[read..]) {
+                        Ok(0) => break,
+                        Ok(n) => read += n,
+                        Err(_) => return false,
+                    }
+                }
+                return true;
+            }
+            CMD_WRITE => return !self.config.read_only && self.file.write_all(data).is_ok(),
+            _ => return false,
+        }
+    }
+}
+
+impl Device for Disk {
+    fn size(&self) -> u16 {
+        return WINDOW_SIZE;
+    }
+
+    fn read(&mut self, offset: u16, _ctx: &mut Context) -> u8 {
+        return get_register(&self.regs, offset);
+    }
+
+    fn write(&mut self, offset: u16, value: u8, ctx: &mut Context) {
+        match offset as usize / 2 {
+            REG_SECTOR | REG_BUFFER | REG_CTRL => set_register(&mut self.regs, offset, value),
+            REG_COMMAND if offset & 1 == 0 => {
+                self.regs[REG_COMMAND] = value as u16;
+                let ok = self.execute(value as u16, ctx);
+                self.regs[REG_STATUS] = if ok {
+                    STATUS_DONE
+                } else {
+                    STATUS_DONE | STATUS_ERROR
+                };
+                if self.regs[REG_CTRL] & CTRL_IRQ != 0 {
+                    ctx.irq = true;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn shutdown(&mut self, _ctx: &mut Context) {
+        _ = self.file.sync_all();
+    }
+}
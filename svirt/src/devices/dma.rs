@@ -0,0 +1,148 @@
+use super::{get_register, set_register, Context, Device};
+
+const REG_SRC: usize = 0;
+const REG_DST: usize = 1;
+const REG_LEN: usize = 2;
+const REG_FILL: usize = 3;
+const REG_CTRL: usize = 4;
+const REG_STATUS: usize = 5;
+const REGISTERS: usize = 6;
+pub const WINDOW_SIZE: u16 = 2 * REGISTERS as u16;
+
+const CTRL_START: u16 = 1 << 0;
+const CTRL_FILL: u16 = 1 << 1;
+const CTRL_IRQ: u16 = 1 << 2;
+
+const STATUS_BUSY: u16 = 1 << 0;
+const STATUS_DONE: u16 = 1 << 1;
+const STATUS_ERROR: u16 = 1 << 2;
+
+const EVENT_WORD: u32 = 0;
+
+#[derive(Clone)]
+pub struct DmaConfig {
+    pub base: u16,
+    pub cycles_per_word: u64,
+}
+
+impl Default for DmaConfig {
+    fn default() -> Self {
+        return Self {
+            base: 0xFF40,
+            cycles_per_word: 1,
+        };
+    }
+}
+
+/// A DMA controller copying or filling blocks of memory in the background,
+/// one word every `cycles_per_word` cycles. Words go over the bus, so other
+/// mapped devices can be read and written too. SRC, DST and LEN advance
+/// while the transfer runs.
+///
+/// | Offset | Register | Description                                   |
+/// | ------ | -------- | --------------------------------------------- |
+/// | 0x00   | SRC      | Source address                                |
+/// | 0x02   | DST      | Destination address                           |
+/// | 0x04   | LEN      | Number of words left to transfer              |
+/// | 0x06   | FILL     | Value written in fill mode                    |
+/// | 0x08   | CTRL     | bit 0 start, bit 1 fill mode, bit 2 interrupt |
+/// | 0x0A   | STATUS   | bit 0 busy, bit 1 done, bit 2 error           |
+pub(crate) struct Dma {
+    config: DmaConfig,
+    regs: [u16; REGISTERS],
+}
+
+impl Dma {
+    pub fn new(config: DmaConfig) -> Self {
+        return Self {
+            config,
+            regs: [0; REGISTERS],
+        };
+    }
+
+    fn start(&mut self, ctx: &mut Context) {
+        self.regs[REG_CTRL] &= !CTRL_START;
+        if self.regs[REG_LEN] == 0 {
+            self.finish(STATUS_DONE, ctx);
+            return;
+        }
+        self.regs[REG_STATUS] = STATUS_BUSY;
+        ctx.schedule(self.config.cycles_per_word, EVENT_WORD);
+    }
+
+    fn finish(&mut self, status: u16, ctx: &mut Context) {
+        self.regs[REG_STATUS] = status;
+        if self.regs[REG_CTRL] & CTRL_IRQ != 0 {
+            ctx.irq = true;
+        }
+    }
+
+    /// Moves a single word over the bus, returning false if part of it
+    /// is not mapped.
+    fn transfer_word(&mut self, ctx: &mut Context) -> bool {
+        let dst = self.regs[REG_DST];
+        if dst == u16::MAX || !ctx.mapped(dst) || !ctx.mapped(dst + 1) {
+            return false;
+        }
+        let word = if self.regs[REG_CTRL] & CTRL_FILL != 0 {
+            self.regs[REG_FILL].to_le_bytes()
+        } else {
+            let src = self.regs[REG_SRC];
+            if src == u16::MAX || !ctx.mapped(src) || !ctx.mapped(src + 1) {
+                return false;
+            }
+            self.regs[REG_SRC] = src.wrapping_add(2);
+            match (ctx.load(src), ctx.load(src + 1)) {
+                (Some(lo), Some(hi)) => [lo, hi],
+                _ => return false,
+            }
+        };
+        if !ctx.store(dst, word[0]) || !ctx.store(dst + 1, word[1]) {
+            return false;
+        }
+        self.regs[REG_DST] = dst.wrapping_add(2);
+        self.regs[REG_LEN] -= 1;
+        return true;
+    }
+}
+
+impl Device for Dma {
+    fn size(&self) -> u16 {
+        return WINDOW_SIZE;
+    }
+
+    fn read(&mut self, offset: u16, _ctx: &mut Context) -> u8 {
+        return get_register(&self.regs, offset);
+    }
+
+    fn write(&mut self, offset: u16, value: u8, ctx: &mut Context) {
+        // The channel registers are locked while a transfer is running.
+        if self.regs[REG_STATUS] & STATUS_BUSY != 0 {
+            return;
+        }
+        match offset as usize / 2 {
+            REG_SRC | REG_DST | REG_LEN | REG_FILL => set_register(&mut self.regs, offset, value),
+            REG_CTRL => {
+                set_register(&mut self.regs, offset, value);
+                if self.regs[REG_CTRL] & CTRL_START != 0 {
+                    self.start(ctx);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn reset(&mut self, _ctx: &mut Context) {
+        self.regs = [0; REGISTERS];
+    }
+
+    fn event(&mut self, _token: u32, ctx: &mut Context) {
+        if !self.transfer_word(ctx) {
+            self.finish(STATUS_DONE | STATUS_ERROR, ctx);
+        } else if self.regs[REG_LEN] == 0 {
+            self.finish(STATUS_DONE, ctx);
+        } else {
+            ctx.schedule(self.config.cycles_per_word, EVENT_WORD);
+        }
+    }
+}
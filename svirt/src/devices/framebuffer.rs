@@ -0,0 +1,260 @@
+use super::{get_register, set_register, Context, Device};
+use std::fs;
+
+const REG_CTRL: usize = 0;
+const REG_VRAM: usize = 1;
+const REG_WIDTH: usize = 2;
+const REG_HEIGHT: usize = 3;
+const REGISTERS: usize = 4;
+pub const WINDOW_SIZE: u16 = 2 * REGISTERS as u16;
+
+const CTRL_ENABLE: u16 = 1 << 0;
+const CTRL_COLOUR: u16 = 1 << 1;
+const CTRL_SNAPSHOT: u16 = 1 << 7;
+
+const EVENT_SNAPSHOT: u32 = 0;
+
+/// The largest width and height, which keeps snapshot buffers small.
+pub const MAX_SIZE: u16 = 1024;
+
+const PALETTE_MONO: [[u8; 3]; 2] = [[0x00, 0x00, 0x00], [0xFF, 0xFF, 0xFF]];
+const PALETTE_COLOUR: [[u8; 3]; 4] = [
+    [0x00, 0x00, 0x00],
+    [0x55, 0x55, 0x55],
+    [0xAA, 0xAA, 0xAA],
+    [0xFF, 0xFF, 0xFF],
+];
+
+#[derive(Clone)]
+pub struct FramebufferConfig {
+    pub base: u16,
+    pub width: u16,
+    pub height: u16,
+    /// Snapshot file, written as PNG if it ends in `.png` and as PPM
+    /// otherwise. A `{}` in the name is replaced by the cycle count.
+    pub output: String,
+    /// Cycles between periodic snapshots, 0 to disable them.
+    pub interval: u64,
+    pub terminal: bool,
+}
+
+impl Default for FramebufferConfig {
+    fn default() -> Self {
+        return Self {
+            base: 0xFF00,
+            width: 128,
+            height: 64,
+            output: String::new(),
+            interval: 0,
+            terminal: false,
+        };
+    }
+}
+
+/// A bitmap display that scans out of main memory. The control registers
+/// select the pixel format and the address of the video memory; pixels are
+/// packed most significant bit first, 1 bit per pixel in monochrome mode and
+/// 2 bits per pixel in 4-colour mode.
+///
+/// | Offset | Register | Description                                        |
+/// | ------ | -------- | -------------------------------------------------- |
+/// | 0x00   | CTRL     | bit 0 enable, bit 1 4-colour mode, bit 7 snapshot  |
+/// | 0x02   | VRAM     | Address of the first pixel row                     |
+/// | 0x04   | WIDTH    | Width in pixels (read only)                        |
+/// | 0x06   | HEIGHT   | Height in pixels (read only)                       |
+pub(crate) struct Framebuffer {
+    config: FramebufferConfig,
+    regs: [u16; REGISTERS],
+}
+
+impl Framebuffer {
+    pub fn new(config: FramebufferConfig) -> Self {
+        let mut regs = [0; REGISTERS];
+        regs[REG_WIDTH] = config.width;
+        regs[REG_HEIGHT] = config.height;
+        return Self { config, regs };
+    }
+
+    fn render(&self, ctx: &Context) -> Vec<u8> {
+        let (width, height) = (self.config.width as usize, self.config.height as usize);
+        let mut rgb = vec![0; width * height * 3];
+        let ctrl = self.regs[REG_CTRL];
+        if ctrl & CTRL_ENABLE == 0 {
+            return rgb;
+        }
+        let bpp = if ctrl & CTRL_COLOUR != 0 { 2 } else { 1 };
+        let vram = self.regs[REG_VRAM] as usize;
+        for y in 0..height {
+            for x in 0..width {
+                let bit = (y * width + x) * bpp;
+                let byte = match ctx.mem.data.get(vram + bit / 8) {
+                    Some(byte) => *byte,
+                    None => 0,
+                };
+                let index = (byte >> (8 - bpp - bit % 8)) & ((1 << bpp) - 1);
+                let colour = if bpp == 2 {
+                    PALETTE_COLOUR[index as usize]
+                } else {
+                    PALETTE_MONO[index as usize]
+                };
+                rgb[(y * width + x) * 3..][..3].copy_from_slice(&colour);
+            }
+        }
+        return rgb;
+    }
+
+    fn snapshot(&self, ctx: &Context) {
+        let rgb = self.render(ctx);
+        let (width, height) = (self.config.width as usize, self.config.height as usize);
+        if !self.config.output.is_empty() {
+            let path = self.config.output.replace("{}", &ctx.cycle.to_string());
+            let contents = if path.to_ascii_lowercase().ends_with(".png") {
+                encode_png(width, height, &rgb)
+            } else {
+                encode_ppm(width, height, &rgb)
+            };
+            if let Err(e) = fs::write(&path, contents) {
+                eprintln!(
+                    " [WARN] Failed to write framebuffer snapshot `{}`: {}",
+                    path, e
+                );
+            }
+        }
+        if self.config.terminal {
+            print_to_terminal(width, height, &rgb);
+        }
+    }
+}
+
+impl Device for Framebuffer {
+    fn size(&self) -> u16 {
+        return WINDOW_SIZE;
+    }
+
+    fn read(&mut self, offset: u16, _ctx: &mut Context) -> u8 {
+        return get_register(&self.regs, offset);
+    }
+
+    fn write(&mut self, offset: u16, value: u8, ctx: &mut Context) {
+        match offset as usize / 2 {
+            REG_CTRL | REG_VRAM => set_register(&mut self.regs, offset, value),
+            _ => return,
+        }
+        if self.regs[REG_CTRL] & CTRL_SNAPSHOT != 0 {
+            self.regs[REG_CTRL] &= !CTRL_SNAPSHOT;
+            self.snapshot(ctx);
+        }
+    }
+
+    fn reset(&mut self, ctx: &mut Context) {
+        self.regs[REG_CTRL] = 0;
+        self.regs[REG_VRAM] = 0;
+        if self.config.interval != 0 {
+            ctx.schedule(self.config.interval, EVENT_SNAPSHOT);
+        }
+    }
+
+    fn event(&mut self, _token: u32, ctx: &mut Context) {
+        self.snapshot(ctx);
+        ctx.schedule(self.config.interval, EVENT_SNAPSHOT);
+    }
+
+    fn shutdown(&mut self, ctx: &mut Context) {
+        self.snapshot(ctx);
+    }
+}
+
+fn encode_ppm(width: usize, height: usize, rgb: &[u8]) -> Vec<u8> {
+    let mut out = format!("P6\n{} {}\n255\n", width, height).into_bytes();
+    out.extend_from_slice(rgb);
+    return out;
+}
+
+/// Encodes an RGB image as a PNG made of uncompressed deflate blocks, which
+/// keeps the encoder tiny at the cost of file size.
+fn encode_png(width: usize, height: usize, rgb: &[u8]) -> Vec<u8> {
+    let mut raw = Vec::with_capacity((width * 3 + 1) * height);
+    for row in rgb.chunks(width * 3) {
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+
+    let mut zlib = vec![0x78, 0x01];
+    let mut blocks = raw.chunks(0xFFFF).peekable();
+    if blocks.peek().is_none() {
+        zlib.extend_from_slice(&[0x01, 0x00, 0x00, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none();
+        let len = block.len() as u16;
+        zlib.push(last as u8);
+        zlib.extend_from_slice(&len.to_le_bytes());
+        zlib.extend_from_slice(&(!len).to_le_bytes());
+        zlib.extend_from_slice(block);
+    }
+    zlib.extend_from_slice(&adler32(&raw).to_be_bytes());
+
+    let mut header = Vec::new();
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    header.extend_from_slice(&[8, 2, 0, 0, 0]);
+
+    let mut out = vec![0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+    png_chunk(&mut out, b"IHDR", &header);
+    png_chunk(&mut out, b"IDAT", &zlib);
+    png_chunk(&mut out, b"IEND", &[]);
+    return out;
+}
+
+fn png_chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = out.len();
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+    let crc = crc32(&out[start..]);
+    out.extend_from_slice(&crc.to_be_bytes());
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    return !crc;
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for byte in data {
+        a = (a + *byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    return (b << 16) | a;
+}
+
+/// Draws the image with upper half block characters so that every
+/// character cell covers two pixel rows.
+fn print_to_terminal(width: usize, height: usize, rgb: &[u8]) {
+    let pixel = |x: usize, y: usize| -> &[u8] {
+        if y >= height {
+            return &[0, 0, 0];
+        }
+        return &rgb[(y * width + x) * 3..][..3];
+    };
+    let mut out = String::new();
+    for y in (0..height).step_by(2) {
+        for x in 0..width {
+            let (top, bottom) = (pixel(x, y), pixel(x, y + 1));
+            out.push_str(&format!(
+                "\x1b[38;2;{};{};{}m\x1b[48;2;{};{};{}m\u{2580}",
+                top[0], top[1], top[2], bottom[0], bottom[1], bottom[2]
+            ));
+        }
+        out.push_str("\x1b[0m\n");
+    }
+    print!("{}", out);
+}
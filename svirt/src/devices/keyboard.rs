@@ -0,0 +1,203 @@
+use super::{get_register, set_register, Context, Device};
+use crate::replay::Input;
+use std::collections::VecDeque;
+use std::io::{self, Read};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+const REG_DATA: usize = 0;
+const REG_STATUS: usize = 1;
+const REG_CTRL: usize = 2;
+const REGISTERS: usize = 3;
+pub const WINDOW_SIZE: u16 = 2 * REGISTERS as u16;
+
+const STATUS_READY: u16 = 1 << 0;
+const STATUS_OVERFLOW: u16 = 1 << 1;
+
+const CTRL_IRQ: u16 = 1 << 0;
+
+#[derive(Clone)]
+pub struct KeyboardConfig {
+    pub base: u16,
+    pub fifo_size: usize,
+    /// Switch the host terminal to raw mode while the machine runs.
+    pub raw: bool,
+}
+
+impl Default for KeyboardConfig {
+    fn default() -> Self {
+        return Self {
+            base: 0xFF10,
+            fifo_size: 16,
+            raw: true,
+        };
+    }
+}
+
+/// Puts the terminal attached to stdin in non-canonical mode without echo
+/// and restores the previous settings when dropped. Signal generation is
+/// left on so that Ctrl-C still stops the machine.
+struct RawMode {
+    saved: libc::termios,
+}
+
+impl RawMode {
+    fn enable() -> Option<Self> {
+        unsafe {
+            if libc::isatty(libc::STDIN_FILENO) != 1 {
+                return None;
+            }
+            let mut saved = std::mem::zeroed::<libc::termios>();
+            if libc::tcgetattr(libc::STDIN_FILENO, &mut saved) != 0 {
+                return None;
+            }
+            let mut raw = saved;
+            raw.c_lflag &= !(libc::ICANON | libc::ECHO | libc::IEXTEN);
+            raw.c_iflag &= !(libc::IXON | libc::ICRNL);
+            raw.c_cc[libc::VMIN] = 1;
+            raw.c_cc[libc::VTIME] = 0;
+            if libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) != 0 {
+                return None;
+            }
+            return Some(Self { saved });
+        }
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.saved);
+        }
+    }
+}
+
+/// A keyboard fed from the host's stdin. Every byte read from the terminal
+/// is a key code; escape sequences are passed through unchanged.
+///
+/// | Offset | Register | Description                                     |
+/// | ------ | -------- | ----------------------------------------------- |
+/// | 0x00   | DATA     | Next key code, popped when the low byte is read |
+/// | 0x02   | STATUS   | bit 0 data ready, bit 1 overflow, high byte count |
+/// | 0x04   | CTRL     | bit 0 raise an interrupt when a key arrives     |
+pub(crate) struct Keyboard {
+    config: KeyboardConfig,
+    regs: [u16; REGISTERS],
+    fifo: VecDeque<u8>,
+    input: Receiver<u8>,
+    raw_mode: Option<RawMode>,
+}
+
+impl Keyboard {
+    /// Creates a keyboard reading stdin, or one that only gets the keys
+    /// of a replayed session unless `live` is set.
+    pub fn new(config: KeyboardConfig, live: bool) -> Self {
+        let raw_mode = if config.raw && live {
+            RawMode::enable()
+        } else {
+            None
+        };
+        let (sender, input) = mpsc::channel();
+        if !live {
+            return Self {
+                config,
+                regs: [0; REGISTERS],
+                fifo: VecDeque::new(),
+                input,
+                raw_mode,
+            };
+        }
+        thread::spawn(move || {
+            for byte in io::stdin().lock().bytes() {
+                match byte {
+                    Ok(b) => {
+                        if sender.send(b).is_err() {
+                            return;
+                        }
+                    }
+                    Err(_) => return,
+                }
+            }
+        });
+        return Self {
+            config,
+            regs: [0; REGISTERS],
+            fifo: VecDeque::new(),
+            input,
+            raw_mode,
+        };
+    }
+
+    fn receive(&mut self, key: u8) {
+        if self.fifo.len() < self.config.fifo_size {
+            self.fifo.push_back(key);
+        } else {
+            self.regs[REG_STATUS] |= STATUS_OVERFLOW;
+        }
+    }
+
+    fn update_status(&mut self) {
+        let mut status = self.regs[REG_STATUS] & STATUS_OVERFLOW;
+        if !self.fifo.is_empty() {
+            status |= STATUS_READY;
+        }
+        status |= (self.fifo.len().min(0xFF) as u16) << 8;
+        self.regs[REG_STATUS] = status;
+    }
+}
+
+impl Device for Keyboard {
+    fn size(&self) -> u16 {
+        return WINDOW_SIZE;
+    }
+
+    fn read(&mut self, offset: u16, _ctx: &mut Context) -> u8 {
+        match offset as usize / 2 {
+            REG_DATA if offset & 1 == 0 => {
+                self.regs[REG_DATA] = self.fifo.pop_front().unwrap_or(0) as u16;
+                self.update_status();
+            }
+            REG_STATUS if offset & 1 == 0 => {
+                let value = get_register(&self.regs, offset);
+                self.regs[REG_STATUS] &= !STATUS_OVERFLOW;
+                return value;
+            }
+            _ => {}
+        }
+        return get_register(&self.regs, offset);
+    }
+
+    fn write(&mut self, offset: u16, value: u8, _ctx: &mut Context) {
+        if offset as usize / 2 == REG_CTRL {
+            set_register(&mut self.regs, offset, value);
+        }
+    }
+
+    fn tick(&mut self, ctx: &mut Context) {
+        let mut received = false;
+        if ctx.log.replaying() {
+            while let Some(Input::Key { code }) = ctx
+                .log
+                .take(ctx.cycle, |input| matches!(input, Input::Key { .. }))
+            {
+                self.receive(code);
+                received = true;
+            }
+        }
+        while let Ok(key) = self.input.try_recv() {
+            ctx.log.push(ctx.cycle, Input::Key { code: key });
+            self.receive(key);
+            received = true;
+        }
+        if received {
+            self.update_status();
+            if self.regs[REG_CTRL] & CTRL_IRQ != 0 {
+                ctx.irq = true;
+            }
+        }
+    }
+
+    fn shutdown(&mut self, _ctx: &mut Context) {
+        self.raw_mode = None;
+    }
+}
@@ -0,0 +1,161 @@
+use crate::replay::InputLog;
+use crate::scheduler::Scheduler;
+use crate::Memory;
+
+pub mod disk;
+pub mod dma;
+pub mod framebuffer;
+pub mod keyboard;
+pub mod rng;
+pub mod rtc;
+
+/// Everything a device is allowed to touch while servicing an access.
+pub(crate) struct Context<'a> {
+    pub mem: &'a mut Memory,
+    pub cycle: u64,
+    /// Set by a device to request an interrupt.
+    pub irq: bool,
+    /// Where devices record, or replay, the inputs they get from the host.
+    pub log: &'a mut InputLog,
+    events: &'a mut Scheduler,
+    device: usize,
+    /// The other devices reachable over the bus, with the index of the
+    /// first one in each slice. Only set while running events.
+    bus: [(usize, &'a mut [MappedDevice]); 2],
+}
+
+impl<'a> Context<'a> {
+    pub fn new(
+        mem: &'a mut Memory,
+        events: &'a mut Scheduler,
+        log: &'a mut InputLog,
+        cycle: u64,
+    ) -> Self {
+        return Self {
+            mem,
+            cycle,
+            irq: false,
+            log,
+            events,
+            device: 0,
+            bus: [(0, &mut []), (0, &mut [])],
+        };
+    }
+
+    /// Makes the devices mapped before and after the selected one reachable
+    /// through `load` and `store`. The selected device itself is borrowed
+    /// by the caller, so it cannot reach its own registers.
+    pub fn connect(&mut self, before: &'a mut [MappedDevice], after: &'a mut [MappedDevice]) {
+        self.bus = [(0, before), (self.device + 1, after)];
+    }
+
+    /// Selects the device the following calls are made on behalf of.
+    pub fn select(&mut self, device: usize) {
+        self.device = device;
+    }
+
+    /// Calls the current device's `event` with `token` after `delay` cycles.
+    pub fn schedule(&mut self, delay: u64, token: u32) {
+        let cycle = self.cycle.saturating_add(delay);
+        self.events.schedule(cycle, self.device, token);
+    }
+
+    /// Whether `address` can be accessed over the bus.
+    pub fn mapped(&self, address: u16) -> bool {
+        let device = self.bus.iter().any(|(_, devices)| {
+            return devices.iter().any(|mapped| mapped.contains(address));
+        });
+        return device || address < self.mem.size;
+    }
+
+    /// Reads a byte over the bus, from a device or from memory.
+    pub fn load(&mut self, address: u16) -> Option<u8> {
+        return self.access(address, None);
+    }
+
+    /// Writes a byte over the bus, to a device or to memory.
+    pub fn store(&mut self, address: u16, value: u8) -> bool {
+        return self.access(address, Some(value)).is_some();
+    }
+
+    fn access(&mut self, address: u16, write: Option<u8>) -> Option<u8> {
+        for (first, devices) in self.bus.iter_mut() {
+            for (i, mapped) in devices.iter_mut().enumerate() {
+                if !mapped.contains(address) {
+                    continue;
+                }
+                let mut ctx = Context::new(self.mem, self.events, self.log, self.cycle);
+                ctx.select(*first + i);
+                let offset = address - mapped.base;
+                let value = match write {
+                    Some(value) => {
+                        mapped.device.write(offset, value, &mut ctx);
+                        value
+                    }
+                    None => mapped.device.read(offset, &mut ctx),
+                };
+                self.irq |= ctx.irq;
+                return Some(value);
+            }
+        }
+        if address >= self.mem.size {
+            return None;
+        }
+        let byte = &mut self.mem.data[address as usize];
+        if let Some(value) = write {
+            *byte = value;
+        }
+        return Some(*byte);
+    }
+}
+
+/// A peripheral whose registers are mapped into the address space.
+/// Offsets passed to `read` and `write` are relative to the device base.
+pub(crate) trait Device {
+    fn size(&self) -> u16;
+    fn read(&mut self, offset: u16, ctx: &mut Context) -> u8;
+    fn write(&mut self, offset: u16, value: u8, ctx: &mut Context);
+
+    /// Called when the machine is reset, before the first instruction.
+    fn reset(&mut self, _ctx: &mut Context) {}
+
+    /// Called once after every executed instruction.
+    fn tick(&mut self, _ctx: &mut Context) {}
+
+    /// Called when an event scheduled with `Context::schedule` is due.
+    fn event(&mut self, _token: u32, _ctx: &mut Context) {}
+
+    /// Called once when the machine stops.
+    fn shutdown(&mut self, _ctx: &mut Context) {}
+}
+
+pub(crate) struct MappedDevice {
+    pub base: u16,
+    pub device: Box<dyn Device>,
+}
+
+impl MappedDevice {
+    pub fn contains(&self, address: u16) -> bool {
+        return address >= self.base && address - self.base < self.device.size();
+    }
+}
+
+/// Reads a little endian 16-bit register out of a register file.
+pub(crate) fn get_register(regs: &[u16], offset: u16) -> u8 {
+    let reg = regs[(offset / 2) as usize];
+    return if offset & 1 == 0 {
+        reg as u8
+    } else {
+        (reg >> 8) as u8
+    };
+}
+
+/// Writes one byte of a little endian 16-bit register in a register file.
+pub(crate) fn set_register(regs: &mut [u16], offset: u16, value: u8) {
+    let reg = &mut regs[(offset / 2) as usize];
+    if offset & 1 == 0 {
+        *reg = (*reg & 0xFF00) | value as u16;
+    } else {
+        *reg = (*reg & 0x00FF) | ((value as u16) << 8);
+    }
+}
@@ -0,0 +1,82 @@
+use super::{get_register, set_register, Context, Device};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+const REG_DATA: usize = 0;
+const REG_SEED: usize = 1;
+const REGISTERS: usize = 2;
+pub const WINDOW_SIZE: u16 = 2 * REGISTERS as u16;
+
+#[derive(Clone)]
+pub struct RngConfig {
+    pub base: u16,
+    /// Fixed seed for reproducible runs, taken from the host otherwise.
+    pub seed: Option<u64>,
+}
+
+impl Default for RngConfig {
+    fn default() -> Self {
+        return Self {
+            base: 0xFF38,
+            seed: None,
+        };
+    }
+}
+
+/// A pseudo random number generator (SplitMix64). Every read of the low
+/// byte of DATA produces a new 16-bit value; writing the high byte of SEED
+/// reseeds the generator with the value of SEED.
+///
+/// | Offset | Register | Description                    |
+/// | ------ | -------- | ------------------------------ |
+/// | 0x00   | DATA     | Random value (read only)       |
+/// | 0x02   | SEED     | Seed loaded on high byte write |
+pub(crate) struct Rng {
+    state: u64,
+    regs: [u16; REGISTERS],
+}
+
+impl Rng {
+    pub fn new(config: RngConfig) -> Self {
+        let state = config.seed.unwrap_or_else(random_seed);
+        return Self {
+            state,
+            regs: [0; REGISTERS],
+        };
+    }
+
+    fn next(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        return z ^ (z >> 31);
+    }
+}
+
+/// A seed taken from the host, different for every run.
+pub(crate) fn random_seed() -> u64 {
+    return RandomState::new().build_hasher().finish();
+}
+
+impl Device for Rng {
+    fn size(&self) -> u16 {
+        return WINDOW_SIZE;
+    }
+
+    fn read(&mut self, offset: u16, _ctx: &mut Context) -> u8 {
+        if offset == 0 {
+            self.regs[REG_DATA] = (self.next() >> 48) as u16;
+        }
+        return get_register(&self.regs, offset);
+    }
+
+    fn write(&mut self, offset: u16, value: u8, _ctx: &mut Context) {
+        if offset as usize / 2 == REG_SEED {
+            set_register(&mut self.regs, offset, value);
+            if offset & 1 == 1 {
+                self.state = self.regs[REG_SEED] as u64;
+            }
+        }
+    }
+}
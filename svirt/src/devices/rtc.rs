@@ -0,0 +1,92 @@
+use super::{get_register, Context, Device};
+use crate::replay::Input;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const REG_SECONDS: usize = 0;
+const REG_MILLIS: usize = 3;
+const REGISTERS: usize = 4;
+pub const WINDOW_SIZE: u16 = 2 * REGISTERS as u16;
+
+#[derive(Clone)]
+pub struct RtcConfig {
+    pub base: u16,
+    /// Unix time the clock starts at in deterministic mode. When set the
+    /// clock advances with the cycle counter instead of the host clock.
+    pub epoch: Option<u64>,
+}
+
+impl Default for RtcConfig {
+    fn default() -> Self {
+        return Self {
+            base: 0xFF30,
+            epoch: None,
+        };
+    }
+}
+
+/// A real-time clock reporting Unix time. Reading the low byte of SECONDS
+/// latches the whole time so that the other registers stay consistent.
+///
+/// | Offset | Register | Description                          |
+/// | ------ | -------- | ------------------------------------ |
+/// | 0x00   | SECONDS  | Bits 0-15 of the Unix time           |
+/// | 0x02   | SECONDS  | Bits 16-31 of the Unix time          |
+/// | 0x04   | SECONDS  | Bits 32-47 of the Unix time          |
+/// | 0x06   | MILLIS   | Milliseconds within the second       |
+pub(crate) struct Rtc {
+    config: RtcConfig,
+    cycles_per_second: u128,
+    regs: [u16; REGISTERS],
+}
+
+impl Rtc {
+    pub fn new(config: RtcConfig, cycles_per_second: u128) -> Self {
+        return Self {
+            config,
+            cycles_per_second,
+            regs: [0; REGISTERS],
+        };
+    }
+
+    fn now_millis(&self, ctx: &mut Context) -> u128 {
+        if let Some(epoch) = self.config.epoch {
+            return epoch as u128 * 1000 + ctx.cycle as u128 * 1000 / self.cycles_per_second;
+        }
+        let logged = ctx
+            .log
+            .take(ctx.cycle, |input| matches!(input, Input::Clock { .. }));
+        if let Some(Input::Clock { millis }) = logged {
+            return millis as u128;
+        }
+        let millis = match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(elapsed) => elapsed.as_millis() as u64,
+            Err(_) => 0,
+        };
+        ctx.log.push(ctx.cycle, Input::Clock { millis });
+        return millis as u128;
+    }
+
+    fn latch(&mut self, ctx: &mut Context) {
+        let millis = self.now_millis(ctx);
+        let seconds = (millis / 1000) as u64;
+        for i in 0..3 {
+            self.regs[REG_SECONDS + i] = (seconds >> (16 * i)) as u16;
+        }
+        self.regs[REG_MILLIS] = (millis % 1000) as u16;
+    }
+}
+
+impl Device for Rtc {
+    fn size(&self) -> u16 {
+        return WINDOW_SIZE;
+    }
+
+    fn read(&mut self, offset: u16, ctx: &mut Context) -> u8 {
+        if offset == 0 {
+            self.latch(ctx);
+        }
+        return get_register(&self.regs, offset);
+    }
+
+    fn write(&mut self, _offset: u16, _value: u8, _ctx: &mut Context) {}
+}
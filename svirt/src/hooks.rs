@@ -0,0 +1,55 @@
+use crate::{Exception, Registers};
+use std::ops::RangeInclusive;
+
+/// The instruction a hook is called for.
+pub struct InstructionEvent {
+    /// Address the instruction was fetched from.
+    pub pc: u16,
+    pub opcode: u16,
+    /// Number of instructions executed before this one.
+    pub cycle: u64,
+}
+
+/// A single byte read or written by an instruction.
+pub struct MemoryAccess {
+    /// Address of the instruction making the access.
+    pub pc: u16,
+    pub address: u16,
+    pub value: u8,
+}
+
+type InstructionHook = Box<dyn FnMut(&InstructionEvent, &Registers)>;
+type MemoryHook = Box<dyn FnMut(&MemoryAccess)>;
+type ExceptionHook = Box<dyn FnMut(Exception, &Registers)>;
+
+/// Callbacks registered by embedders through `VirtualMachine::on_*`.
+#[derive(Default)]
+pub(crate) struct Hooks {
+    pub before_instruction: Vec<InstructionHook>,
+    pub after_instruction: Vec<InstructionHook>,
+    pub memory_read: Vec<(RangeInclusive<u16>, MemoryHook)>,
+    pub memory_write: Vec<(RangeInclusive<u16>, MemoryHook)>,
+    pub exception: Vec<ExceptionHook>,
+}
+
+impl Hooks {
+    pub fn instruction(hooks: &mut [InstructionHook], event: &InstructionEvent, regs: &Registers) {
+        for hook in hooks.iter_mut() {
+            hook(event, regs);
+        }
+    }
+
+    pub fn memory(hooks: &mut [(RangeInclusive<u16>, MemoryHook)], access: &MemoryAccess) {
+        for (range, hook) in hooks.iter_mut() {
+            if range.contains(&access.address) {
+                hook(access);
+            }
+        }
+    }
+
+    pub fn exception(&mut self, exception: Exception, regs: &Registers) {
+        for hook in self.exception.iter_mut() {
+            hook(exception, regs);
+        }
+    }
+}
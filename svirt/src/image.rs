@@ -0,0 +1,209 @@
+/// A block of bytes that should be placed at `address` when loading an image.
+pub struct Segment {
+    pub address: u16,
+    pub data: Vec<u8>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ImageFormat {
+    Binary,
+    IntelHex,
+    SRecord,
+}
+
+impl ImageFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        return match name {
+            "bin" | "binary" => Some(Self::Binary),
+            "ihex" | "hex" => Some(Self::IntelHex),
+            "srec" | "s19" | "mot" => Some(Self::SRecord),
+            _ => None,
+        };
+    }
+
+    /// Guesses the format of a firmware file from its extension, falling
+    /// back to a raw binary image.
+    pub fn from_path(path: &str) -> Self {
+        let extension = match path.rsplit_once('.') {
+            Some((_, ext)) => ext.to_ascii_lowercase(),
+            None => return Self::Binary,
+        };
+        return match extension.as_str() {
+            "ihex" | "hex" => Self::IntelHex,
+            "srec" | "s19" | "mot" => Self::SRecord,
+            _ => Self::Binary,
+        };
+    }
+}
+
+/// Parses the data records of an Intel HEX file. Only 16-bit addresses are
+/// meaningful to RSC1, so extended address records must be zero.
+pub fn parse_intel_hex(text: &str) -> Result<Vec<Segment>, String> {
+    let mut segments = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let record = match line.strip_prefix(':') {
+            Some(record) => decode_record(record, i)?,
+            None => return Err(format!("line {}: missing record mark `:`", i + 1)),
+        };
+        if record.len() < 5 || record[0] as usize != record.len() - 5 {
+            return Err(format!("line {}: invalid record length", i + 1));
+        }
+        if record.iter().fold(0u8, |acc, b| acc.wrapping_add(*b)) != 0 {
+            return Err(format!("line {}: checksum mismatch", i + 1));
+        }
+        let address = ((record[1] as u16) << 8) | record[2] as u16;
+        let data = &record[4..record.len() - 1];
+        match record[3] {
+            0x00 => segments.push(Segment {
+                address,
+                data: data.to_vec(),
+            }),
+            0x01 => break,
+            0x02 | 0x04 => {
+                if data.iter().any(|b| *b != 0) {
+                    return Err(format!("line {}: address exceeds 16 bits", i + 1));
+                }
+            }
+            0x03 | 0x05 => {}
+            kind => return Err(format!("line {}: unknown record type {:0>2X}", i + 1, kind)),
+        }
+    }
+    return Ok(segments);
+}
+
+/// Parses the S1 data records of a Motorola S-record file. Header, count
+/// and terminator records are validated but otherwise ignored.
+pub fn parse_srecord(text: &str) -> Result<Vec<Segment>, String> {
+    let mut segments = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let (kind, record) = match line.strip_prefix('S') {
+            Some(rest) if rest.is_char_boundary(1) => (&rest[..1], decode_record(&rest[1..], i)?),
+            _ => return Err(format!("line {}: missing record mark `S`", i + 1)),
+        };
+        if record.is_empty() || record[0] as usize != record.len() - 1 {
+            return Err(format!("line {}: invalid record length", i + 1));
+        }
+        if record.iter().fold(0u8, |acc, b| acc.wrapping_add(*b)) != 0xFF {
+            return Err(format!("line {}: checksum mismatch", i + 1));
+        }
+        match kind {
+            "1" => {
+                if record.len() < 4 {
+                    return Err(format!("line {}: invalid record length", i + 1));
+                }
+                segments.push(Segment {
+                    address: ((record[1] as u16) << 8) | record[2] as u16,
+                    data: record[3..record.len() - 1].to_vec(),
+                });
+            }
+            "2" | "3" => return Err(format!("line {}: address exceeds 16 bits", i + 1)),
+            "0" | "5" | "6" | "7" | "8" | "9" => {}
+            _ => return Err(format!("line {}: unknown record type S{}", i + 1, kind)),
+        }
+    }
+    return Ok(segments);
+}
+
+fn decode_record(digits: &str, line: usize) -> Result<Vec<u8>, String> {
+    if !digits.is_ascii() {
+        return Err(format!("line {}: invalid hex digit", line + 1));
+    }
+    if !digits.len().is_multiple_of(2) {
+        return Err(format!("line {}: odd number of hex digits", line + 1));
+    }
+    let mut bytes = Vec::new();
+    for i in (0..digits.len()).step_by(2) {
+        match u8::from_str_radix(&digits[i..i + 2], 16) {
+            Ok(b) => bytes.push(b),
+            Err(_) => return Err(format!("line {}: invalid hex digit", line + 1)),
+        }
+    }
+    return Ok(bytes);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The address and bytes of each segment.
+    fn contents(segments: &[Segment]) -> Vec<(u16, Vec<u8>)> {
+        return segments
+            .iter()
+            .map(|s| (s.address, s.data.clone()))
+            .collect();
+    }
+
+    #[test]
+    fn reads_the_intel_hex_written_by_sasm() {
+        let hex = ":0401000001020304F1\n:02FFFE003412BB\n:00000001FF\n";
+        let segments = parse_intel_hex(hex).expect("a valid image");
+        assert_eq!(
+            contents(&segments),
+            vec![
+                (0x0100, vec![0x01, 0x02, 0x03, 0x04]),
+                (0xFFFE, vec![0x34, 0x12]),
+            ]
+        );
+    }
+
+    #[test]
+    fn intel_hex_stops_at_the_end_of_file_record() {
+        let hex = ":0100000042BD\n:00000001FF\n:01000100FFFF\n";
+        let segments = parse_intel_hex(hex).expect("a valid image");
+        assert_eq!(contents(&segments), vec![(0x0000, vec![0x42])]);
+    }
+
+    #[test]
+    fn intel_hex_rejects_bad_records() {
+        let error = |text: &str| parse_intel_hex(text).err().expect("an error");
+        assert_eq!(error(":0401000001020304F2"), "line 1: checksum mismatch");
+        assert_eq!(error("\n0100000042BD"), "line 2: missing record mark `:`");
+        assert_eq!(error(":0500000042B9"), "line 1: invalid record length");
+        assert_eq!(error(":0100000042B"), "line 1: odd number of hex digits");
+        assert_eq!(error(":01000000G2BD"), "line 1: invalid hex digit");
+        assert_eq!(error(":020000040001F9"), "line 1: address exceeds 16 bits");
+        assert_eq!(error(":00000006FA"), "line 1: unknown record type 06");
+    }
+
+    #[test]
+    fn reads_the_srecords_written_by_sasm() {
+        let srec = "S00400007487\nS107010001020304ED\nS5030001FB\nS9030000FC\n";
+        let segments = parse_srecord(srec).expect("a valid image");
+        assert_eq!(
+            contents(&segments),
+            vec![(0x0100, vec![0x01, 0x02, 0x03, 0x04])]
+        );
+    }
+
+    #[test]
+    fn srecords_reject_bad_records() {
+        let error = |text: &str| parse_srecord(text).err().expect("an error");
+        assert_eq!(error("S107010001020304EE"), "line 1: checksum mismatch");
+        assert_eq!(
+            error("107010001020304ED"),
+            "line 1: missing record mark `S`"
+        );
+        assert_eq!(error("S1080100010203ED"), "line 1: invalid record length");
+        assert_eq!(
+            error("S20800000001020304ED"),
+            "line 1: address exceeds 16 bits"
+        );
+        assert_eq!(error("S4030000FC"), "line 1: unknown record type S4");
+    }
+
+    #[test]
+    fn formats_are_guessed_from_extensions() {
+        assert!(ImageFormat::from_path("boot.HEX") == ImageFormat::IntelHex);
+        assert!(ImageFormat::from_path("boot.s19") == ImageFormat::SRecord);
+        assert!(ImageFormat::from_path("boot.bin") == ImageFormat::Binary);
+        assert!(ImageFormat::from_path("boot") == ImageFormat::Binary);
+    }
+}
@@ -1,17 +1,71 @@
 use num_derive::ToPrimitive;
 use num_traits::ToPrimitive;
+use std::collections::VecDeque;
 use std::fs;
 use std::io::ErrorKind;
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::time::Instant;
 
-pub mod machine;
+pub mod config;
+pub mod devices;
+pub mod hooks;
+pub mod image;
+mod remote;
+mod replay;
+mod scheduler;
+mod semihosting;
+pub mod state;
 
-enum Exception {
+use devices::disk::{Disk, DiskConfig};
+use devices::dma::{Dma, DmaConfig};
+use devices::framebuffer::{Framebuffer, FramebufferConfig};
+use devices::keyboard::{Keyboard, KeyboardConfig};
+use devices::rng::{Rng, RngConfig};
+use devices::rtc::{Rtc, RtcConfig};
+use devices::{Context, MappedDevice};
+use hooks::{Hooks, InstructionEvent, MemoryAccess};
+use image::{ImageFormat, Segment};
+use remote::Remote;
+use replay::{Input, InputLog};
+use scheduler::Scheduler;
+use semihosting::Semihosting;
+use serde::{Deserialize, Serialize};
+use state::{CoreFile, MachineState, MemoryRange, StopReason, TraceEntry};
+
+// Interrupts are only taken while IE is set. Entering the handler clears IE
+// and sets IRQ so that the handler can tell interrupts and exceptions apart.
+const FG_IE: u16 = 1 << 10;
+const FG_IRQ: u16 = 1 << 11;
+
+// A fault raised while one of these is still set is a double fault.
+const FG_FAULTS: u16 = (1 << 15) | (1 << 14) | (1 << 13) | (1 << 12);
+
+/// Faults raised by an instruction. Each one sets its own bit in fg before
+/// the handler in c1 is entered.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Exception {
+    /// Invalid operand, e.g. a register the instruction cannot use.
     IOP,
+    /// Access outside of memory.
     SEG,
+    /// Unaligned jump target.
     UNA,
+    /// Opcode that does not decode to any instruction.
+    ILL,
+}
+
+impl Exception {
+    fn flag(self) -> u16 {
+        match self {
+            Exception::IOP => return 1 << 15,
+            Exception::SEG => return 1 << 14,
+            Exception::UNA => return 1 << 13,
+            Exception::ILL => return 1 << 12,
+        }
+    }
 }
 
 enum Instruction {
@@ -50,20 +104,60 @@ pub struct Configuration {
     pub initial_pc: u16,
     pub memory_size: u16,
     pub firmware_file: String,
+    pub firmware_format: Option<ImageFormat>,
     pub verbose: bool,
+    /// Where to write a core file when the machine stops on an unhandled
+    /// fault, if anywhere.
+    pub core_file: Option<PathBuf>,
+    /// Number of executed instructions kept for the core file.
+    pub trace_length: usize,
+    pub framebuffer: Option<FramebufferConfig>,
+    pub keyboard: Option<KeyboardConfig>,
+    pub disk: Option<DiskConfig>,
+    pub rtc: Option<RtcConfig>,
+    pub rng: Option<RngConfig>,
+    pub dma: Option<DmaConfig>,
+    pub semihosting: bool,
+    /// Arguments handed to firmware through the semihosting interface.
+    pub args: Vec<String>,
+    /// Address of the remote control server, `unix:PATH` or `tcp:PORT`.
+    pub listen: Option<String>,
+    /// Start paused, waiting for a remote client to resume the machine.
+    pub paused: bool,
+    /// Log every nondeterministic input to this file.
+    pub record: Option<String>,
+    /// Take the nondeterministic inputs from a log written with `record`.
+    pub replay: Option<String>,
 }
 
-impl Configuration {
-    pub fn default() -> Self {
+impl Default for Configuration {
+    fn default() -> Self {
         return Self {
             cycles_per_second: 32,
             initial_pc: 0,
             memory_size: 0x4000,
             firmware_file: String::new(),
+            firmware_format: None,
             verbose: false,
+            core_file: None,
+            trace_length: 64,
+            framebuffer: None,
+            keyboard: None,
+            disk: None,
+            rtc: None,
+            rng: None,
+            dma: None,
+            semihosting: false,
+            args: Vec::new(),
+            listen: None,
+            paused: false,
+            record: None,
+            replay: None,
         };
     }
+}
 
+impl Configuration {
     pub fn dump_to_stdout(&self) {
         println!();
         println!(" ----- VM CFG -----");
@@ -71,34 +165,95 @@ impl Configuration {
         println!(" iPC={}", self.initial_pc);
         println!(" MEM={}", self.memory_size);
         println!(" FWF={}", self.firmware_file);
+        if let Some(fb) = &self.framebuffer {
+            println!(" FB ={:0>4X} ({}x{})", fb.base, fb.width, fb.height);
+        }
+        if let Some(kbd) = &self.keyboard {
+            println!(" KBD={:0>4X}", kbd.base);
+        }
+        if let Some(disk) = &self.disk {
+            println!(" DSK={:0>4X} ({})", disk.base, disk.image);
+        }
+        if let Some(rtc) = &self.rtc {
+            match rtc.epoch {
+                Some(epoch) => println!(" RTC={:0>4X} (epoch {})", rtc.base, epoch),
+                None => println!(" RTC={:0>4X}", rtc.base),
+            }
+        }
+        if let Some(rng) = &self.rng {
+            match rng.seed {
+                Some(seed) => println!(" RNG={:0>4X} (seed {})", rng.base, seed),
+                None => println!(" RNG={:0>4X}", rng.base),
+            }
+        }
+        if let Some(dma) = &self.dma {
+            println!(
+                " DMA={:0>4X} ({} cycles/word)",
+                dma.base, dma.cycles_per_word
+            );
+        }
+        if self.semihosting {
+            println!(" SEMIHOSTING ARGS={:?}", self.args);
+        }
+        if let Some(listen) = &self.listen {
+            println!(" LISTEN={}", listen);
+        }
+        if let Some(record) = &self.record {
+            println!(" RECORD={}", record);
+        }
+        if let Some(replay) = &self.replay {
+            println!(" REPLAY={}", replay);
+        }
         println!();
     }
 }
 
 struct Firmware {
-    data: Box<[u8]>,
-    size: u16,
+    segments: Vec<Segment>,
+    // Raw binaries are placed at the initial PC, record formats carry
+    // their own load addresses.
+    absolute: bool,
 }
 
 impl Firmware {
-    pub fn from_file(path: &String) -> Self {
-        match fs::read(path) {
-            Ok(bytes) => {
-                return Self {
-                    size: bytes.len() as u16,
-                    data: bytes.into_boxed_slice(),
-                }
-            }
-            Err(e) => {
-                eprint!("Failed to load firmware: ");
-                match e.kind() {
-                    ErrorKind::PermissionDenied => eprintln!("permission denied."),
-                    ErrorKind::NotFound => eprintln!("file not found."),
-                    _ => eprintln!("unknown error."),
-                }
-                panic!("{}", e);
-            }
+    pub fn load(path: &str, format: Option<ImageFormat>) -> Result<Self, String> {
+        let bytes = match fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(e) => match e.kind() {
+                ErrorKind::PermissionDenied => return Err("permission denied".to_string()),
+                ErrorKind::NotFound => return Err("file not found".to_string()),
+                _ => return Err(e.to_string()),
+            },
+        };
+        let format = format.unwrap_or_else(|| ImageFormat::from_path(path));
+        if format == ImageFormat::Binary {
+            return Ok(Self {
+                segments: vec![Segment {
+                    address: 0,
+                    data: bytes,
+                }],
+                absolute: false,
+            });
         }
+        let text = String::from_utf8_lossy(&bytes);
+        let parsed = match format {
+            ImageFormat::IntelHex => image::parse_intel_hex(&text),
+            _ => image::parse_srecord(&text),
+        };
+        return parsed.map(|segments| Self {
+            segments,
+            absolute: true,
+        });
+    }
+
+    /// Whether every segment fits in `size` bytes of memory when loaded
+    /// with the program counter at `pc`.
+    pub fn fits(&self, pc: u16, size: u16) -> bool {
+        let base = if self.absolute { 0 } else { pc as usize };
+        return self
+            .segments
+            .iter()
+            .all(|segment| base + segment.address as usize + segment.data.len() <= size as usize);
     }
 
     pub fn default() -> Self {
@@ -111,8 +266,11 @@ impl Firmware {
             0x00, 0x62,
         ];
         return Self {
-            size: default.len() as u16,
-            data: default.into_boxed_slice(),
+            segments: vec![Segment {
+                address: 0,
+                data: default,
+            }],
+            absolute: false,
         };
     }
 }
@@ -127,27 +285,24 @@ impl Memory {
         if alloc_size == 0 {
             panic!("Cannot create memory with size of 0");
         }
-        let mut vec = Vec::new();
-        for _ in 0..alloc_size {
-            vec.push(0);
-        }
         return Self {
-            data: vec.into_boxed_slice(),
+            data: vec![0; alloc_size as usize].into_boxed_slice(),
             size: alloc_size,
         };
     }
 }
 
-struct Registers {
-    r: [u16; 8],
-    c: [u16; 2],
-    sp: u16,
-    fg: u16,
-    pc: u16,
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Registers {
+    pub r: [u16; 8],
+    pub c: [u16; 2],
+    pub sp: u16,
+    pub fg: u16,
+    pub pc: u16,
 }
 
 impl Registers {
-    pub fn new() -> Self {
+    fn new() -> Self {
         return Self {
             r: [0; 8],
             c: [0; 2],
@@ -163,75 +318,531 @@ pub struct VirtualMachine {
     firmware: Firmware,
     mem: Memory,
     regs: Registers,
+    devices: Vec<MappedDevice>,
+    cycles: u64,
+    events: Scheduler,
+    irq_pending: bool,
+    semihosting: Option<Semihosting>,
+    hooks: Hooks,
+    remote: Option<Remote>,
+    stop_reason: Option<StopReason>,
+    trace: VecDeque<TraceEntry>,
+    input_log: InputLog,
     pub should_run: Arc<AtomicBool>,
 }
 
 impl VirtualMachine {
-    pub fn new(config: Configuration) -> Self {
+    /// Builds a machine from `config`, opening the files and sockets it
+    /// names. Fails if one of them cannot be used.
+    pub fn new(config: Configuration) -> Result<Self, String> {
         let firmware = if config.firmware_file.is_empty() {
             Firmware::default()
         } else {
-            Firmware::from_file(&config.firmware_file)
+            match Firmware::load(&config.firmware_file, config.firmware_format) {
+                Ok(firmware) => firmware,
+                Err(e) => {
+                    return Err(format!(
+                        "cannot load firmware `{}`: {}",
+                        config.firmware_file, e
+                    ))
+                }
+            }
         };
+        if !firmware.fits(config.initial_pc, config.memory_size) {
+            return Err(format!(
+                "firmware does not fit in {} bytes of memory",
+                config.memory_size
+            ));
+        }
         let mem = Memory::new(config.memory_size);
         let regs = Registers::new();
-        return Self {
+        let log = match (&config.record, &config.replay) {
+            (Some(path), _) => InputLog::record(path),
+            (_, Some(path)) => InputLog::replay(path),
+            _ => Ok(InputLog::off()),
+        };
+        let mut input_log = match log {
+            Ok(log) => log,
+            Err(e) => return Err(format!("cannot open input log: {}", e)),
+        };
+        let mut devices = Vec::new();
+        if let Some(fb) = &config.framebuffer {
+            devices.push(MappedDevice {
+                base: fb.base,
+                device: Box::new(Framebuffer::new(fb.clone())),
+            });
+        }
+        if let Some(kbd) = &config.keyboard {
+            devices.push(MappedDevice {
+                base: kbd.base,
+                device: Box::new(Keyboard::new(kbd.clone(), !input_log.replaying())),
+            });
+        }
+        if let Some(disk) = &config.disk {
+            match Disk::open(disk.clone()) {
+                Ok(device) => devices.push(MappedDevice {
+                    base: disk.base,
+                    device: Box::new(device),
+                }),
+                Err(e) => return Err(format!("cannot load disk image: {}", e)),
+            }
+        }
+        if let Some(rtc) = &config.rtc {
+            devices.push(MappedDevice {
+                base: rtc.base,
+                device: Box::new(Rtc::new(rtc.clone(), config.cycles_per_second)),
+            });
+        }
+        if let Some(rng) = &config.rng {
+            // A host seed is an input like any other and has to be logged.
+            let mut rng = rng.clone();
+            if rng.seed.is_none() {
+                let seed = match input_log.take(0, |input| matches!(input, Input::Seed { .. })) {
+                    Some(Input::Seed { seed }) => seed,
+                    _ => devices::rng::random_seed(),
+                };
+                input_log.push(0, Input::Seed { seed });
+                rng.seed = Some(seed);
+            }
+            devices.push(MappedDevice {
+                base: rng.base,
+                device: Box::new(Rng::new(rng)),
+            });
+        }
+        if let Some(dma) = &config.dma {
+            devices.push(MappedDevice {
+                base: dma.base,
+                device: Box::new(Dma::new(dma.clone())),
+            });
+        }
+        let semihosting = if config.semihosting {
+            Some(Semihosting::new(config.args.clone()))
+        } else {
+            None
+        };
+        let remote = match &config.listen {
+            Some(address) => match Remote::listen(address, config.paused) {
+                Ok(remote) => Some(remote),
+                Err(e) => return Err(format!("cannot start remote control: {}", e)),
+            },
+            None => None,
+        };
+        return Ok(Self {
             config,
             firmware,
             mem,
             regs,
+            devices,
+            cycles: 0,
+            events: Scheduler::default(),
+            irq_pending: false,
+            semihosting,
+            hooks: Hooks::default(),
+            remote,
+            stop_reason: None,
+            trace: VecDeque::new(),
+            input_log,
             should_run: Arc::new(AtomicBool::new(true)),
-        };
+        });
     }
 
     pub fn dump_to_stdout(&self) {
-        println!();
-        println!(" ---- VM STATE ----");
-        println!(" R0={:0>4X}    R1={:0>4X}", self.regs.r[0], self.regs.r[1]);
-        println!(" R2={:0>4X}    R3={:0>4X}", self.regs.r[2], self.regs.r[3]);
-        println!(" R4={:0>4X}    R5={:0>4X}", self.regs.r[4], self.regs.r[5]);
-        println!(" C0={:0>4X}    C1={:0>4X}", self.regs.c[0], self.regs.c[1]);
-        println!(" FG={:0>4X}    SP={:0>4X}", self.regs.fg, self.regs.sp);
-        println!(" PC={:0>4X}              ", self.regs.pc);
+        print!("{}", self.state(&[]));
+    }
+
+    /// Captures the registers, cycle count and stop reason along with the
+    /// contents of `ranges`. Ranges are clipped to memory; mapped devices
+    /// are not read so that dumping has no side effects.
+    pub fn state(&self, ranges: &[RangeInclusive<u16>]) -> MachineState {
+        let mut memory = Vec::new();
+        for range in ranges.iter() {
+            let start = *range.start() as usize;
+            let end = (*range.end() as usize + 1).min(self.mem.data.len());
+            if start < end {
+                memory.push(MemoryRange {
+                    start: start as u16,
+                    data: self.mem.data[start..end].to_vec(),
+                });
+            }
+        }
+        return MachineState {
+            registers: self.regs.clone(),
+            flags: state::decode_flags(self.regs.fg),
+            cycles: self.cycles,
+            stop_reason: self.stop_reason,
+            memory,
+        };
+    }
+
+    /// Why `run` returned, or None while the machine has not stopped.
+    pub fn stop_reason(&self) -> Option<StopReason> {
+        return self.stop_reason;
     }
 
+    pub fn registers(&self) -> &Registers {
+        return &self.regs;
+    }
+
+    /// Calls `hook` before every instruction, with the registers as they are
+    /// before it executes.
+    pub fn on_before_instruction(
+        &mut self,
+        hook: impl FnMut(&InstructionEvent, &Registers) + 'static,
+    ) {
+        self.hooks.before_instruction.push(Box::new(hook));
+    }
+
+    /// Calls `hook` after every instruction, once its result (including any
+    /// exception) has been applied to the registers.
+    pub fn on_after_instruction(
+        &mut self,
+        hook: impl FnMut(&InstructionEvent, &Registers) + 'static,
+    ) {
+        self.hooks.after_instruction.push(Box::new(hook));
+    }
+
+    /// Calls `hook` for every byte an instruction loads from `range`,
+    /// including device registers.
+    pub fn on_memory_read(
+        &mut self,
+        range: RangeInclusive<u16>,
+        hook: impl FnMut(&MemoryAccess) + 'static,
+    ) {
+        self.hooks.memory_read.push((range, Box::new(hook)));
+    }
+
+    /// Calls `hook` for every byte an instruction stores to `range`,
+    /// including device registers. Writes made by devices and semihosting
+    /// calls are not reported.
+    pub fn on_memory_write(
+        &mut self,
+        range: RangeInclusive<u16>,
+        hook: impl FnMut(&MemoryAccess) + 'static,
+    ) {
+        self.hooks.memory_write.push((range, Box::new(hook)));
+    }
+
+    /// Calls `hook` when an instruction faults, before the handler is
+    /// entered.
+    pub fn on_exception(&mut self, hook: impl FnMut(Exception, &Registers) + 'static) {
+        self.hooks.exception.push(Box::new(hook));
+    }
+
+    /// Puts the machine in its power-on state: the registers, cycle count,
+    /// pending interrupt and stop reason are cleared, the firmware is loaded
+    /// again and every device is reset.
     pub fn reset(&mut self) {
+        self.regs = Registers::new();
         self.regs.pc = self.config.initial_pc;
-        for i in 0..self.firmware.size {
-            self.mem.data[(self.regs.pc + i) as usize] = self.firmware.data[i as usize];
+        self.cycles = 0;
+        self.irq_pending = false;
+        self.stop_reason = None;
+        self.trace.clear();
+        let base = if self.firmware.absolute {
+            0
+        } else {
+            self.regs.pc
+        };
+        // Only firmware that fits is ever accepted, see `Firmware::fits`.
+        for segment in self.firmware.segments.iter() {
+            let start = base as usize + segment.address as usize;
+            let end = start + segment.data.len();
+            self.mem.data[start..end].copy_from_slice(&segment.data);
+        }
+        self.events.clear();
+        let mut ctx = Context::new(
+            &mut self.mem,
+            &mut self.events,
+            &mut self.input_log,
+            self.cycles,
+        );
+        for (id, mapped) in self.devices.iter_mut().enumerate() {
+            ctx.select(id);
+            mapped.device.reset(&mut ctx);
         }
     }
 
     pub fn run(&mut self) {
+        self.stop_reason = None;
         let delta_ceil = 1_000_000_000 / self.config.cycles_per_second;
         let mut before = Instant::now();
         let mut delta = 0;
         while self.should_run.load(Ordering::Relaxed) {
+            if self.serve_remote() {
+                // Do not try to catch up on the time spent paused.
+                before = Instant::now();
+                delta = 0;
+                continue;
+            }
             let now = Instant::now();
             delta += (now - before).as_nanos();
             if delta >= delta_ceil {
-                match self.step() {
-                    Ok(s) => self.regs.pc += s,
-                    Err(e) => {
-                        match e {
-                            Exception::IOP => self.regs.fg |= 1 << 15,
-                            Exception::SEG => self.regs.fg |= 1 << 14,
-                            Exception::UNA => self.regs.fg |= 1 << 13,
-                        }
-                        self.regs.sp -= 1;
-                        self.mem.data[self.regs.sp as usize] = ((self.regs.pc & 0xFF00) >> 8) as u8;
-                        self.regs.sp -= 1;
-                        self.mem.data[self.regs.sp as usize] = (self.regs.pc & 0x00FF) as u8;
-                        self.regs.pc = self.regs.c[1];
-                    }
-                }
+                self.cycle();
                 delta -= delta_ceil;
                 if delta >= delta_ceil {
-                    println!(" [WARN] Running late by {}ns", delta);
+                    eprintln!(" [WARN] Running late by {}ns", delta);
                 }
             }
             before = now;
         }
+        self.stop_reason.get_or_insert(StopReason::Interrupted);
+    }
+
+    /// Executes a single instruction right away, without pacing it to
+    /// `cycles_per_second`. Returns why the machine stopped, if it did; a
+    /// stopped machine executes nothing until it is reset.
+    pub fn step(&mut self) -> Option<StopReason> {
+        if self.stop_reason.is_none() && !self.should_run.load(Ordering::Relaxed) {
+            self.stop_reason = Some(StopReason::Interrupted);
+        }
+        if self.stop_reason.is_none() {
+            self.cycle();
+        }
+        return self.stop_reason;
+    }
+
+    /// Steps through up to `cycles` instructions, returning early if the
+    /// machine stops.
+    pub fn run_for(&mut self, cycles: u64) -> Option<StopReason> {
+        for _ in 0..cycles {
+            if let Some(reason) = self.step() {
+                return Some(reason);
+            }
+        }
+        return None;
+    }
+
+    /// Executes one instruction, advances the devices, fires the events that
+    /// are due and takes a pending interrupt if interrupts are enabled.
+    fn cycle(&mut self) {
+        let event = InstructionEvent {
+            pc: self.regs.pc,
+            opcode: self.fetch(),
+            cycle: self.cycles,
+        };
+        Hooks::instruction(&mut self.hooks.before_instruction, &event, &self.regs);
+        if self.config.trace_length > 0 {
+            if self.trace.len() == self.config.trace_length {
+                self.trace.pop_front();
+            }
+            self.trace.push_back(TraceEntry {
+                cycle: event.cycle,
+                pc: event.pc,
+                opcode: event.opcode,
+            });
+        }
+        let result = self.execute(event.opcode);
+        self.cycles += 1;
+        match result {
+            Ok(s) => self.regs.pc += s,
+            Err(e) => self.raise(e),
+        }
+        Hooks::instruction(&mut self.hooks.after_instruction, &event, &self.regs);
+        self.tick_devices();
+        self.run_events();
+        if self.irq_pending && self.regs.fg & FG_IE != 0 && self.stop_reason.is_none() {
+            let fg = self.regs.fg;
+            self.regs.fg = (fg & !FG_IE) | FG_IRQ;
+            if self.enter_handler() {
+                self.irq_pending = false;
+                self.input_log.push(self.cycles, Input::Interrupt);
+                self.input_log
+                    .expect(self.cycles, |input| matches!(input, Input::Interrupt));
+            } else {
+                // Stays pending until a handler can be entered.
+                self.regs.fg = fg;
+            }
+        }
+        self.input_log.check(self.cycles);
+    }
+
+    /// Records `e` in fg and enters its handler. A fault raised while the
+    /// bit of an earlier one is still set, or one whose handler cannot be
+    /// entered, stops the machine instead.
+    fn raise(&mut self, e: Exception) {
+        self.hooks.exception(e, &self.regs);
+        let nested = self.regs.fg & FG_FAULTS != 0;
+        self.regs.fg |= e.flag();
+        if nested || !self.enter_handler() {
+            self.halt_on_fault(e);
+        }
+    }
+
+    fn halt_on_fault(&mut self, exception: Exception) {
+        let pc = self.regs.pc;
+        self.stop_reason = Some(StopReason::Fault { exception, pc });
+        // A remote client can still inspect the machine and reset it.
+        match self.remote.as_mut() {
+            Some(remote) => remote.paused = true,
+            None => self.should_run.store(false, Ordering::Relaxed),
+        }
+        let path = match &self.config.core_file {
+            Some(path) => path,
+            None => return,
+        };
+        let core = CoreFile {
+            state: self.state(&[0..=self.mem.size - 1]),
+            trace: self.trace.iter().cloned().collect(),
+        };
+        match core.write(path) {
+            Ok(()) => eprintln!(
+                "Unhandled {:?} at {:0>4X}, core written to `{}`.",
+                exception,
+                pc,
+                path.display()
+            ),
+            Err(e) => eprintln!("Failed to write core file: {}.", e),
+        }
+    }
+
+    /// Pushes the program counter and jumps to the handler in c1. Returns
+    /// false without touching the machine if c1 is zero or not a valid
+    /// instruction address, or if the stack has no room for the return
+    /// address.
+    fn enter_handler(&mut self) -> bool {
+        let handler = self.regs.c[1];
+        let sp = self.regs.sp;
+        if handler == 0
+            || handler & 1 != 0
+            || handler as usize + 2 > self.mem.data.len()
+            || sp < 2
+            || sp > self.mem.size
+        {
+            return false;
+        }
+        self.regs.sp -= 1;
+        self.mem.data[self.regs.sp as usize] = ((self.regs.pc & 0xFF00) >> 8) as u8;
+        self.regs.sp -= 1;
+        self.mem.data[self.regs.sp as usize] = (self.regs.pc & 0x00FF) as u8;
+        self.regs.pc = handler;
+        return true;
+    }
+
+    /// The status passed to the semihosting EXIT call, if firmware made it.
+    pub fn exit_status(&self) -> Option<u16> {
+        return self
+            .semihosting
+            .as_ref()
+            .and_then(|host| host.exit_status());
+    }
+
+    /// Lets every device finish its work, e.g. flush pending output.
+    pub fn shutdown(&mut self) {
+        let mut ctx = Context::new(
+            &mut self.mem,
+            &mut self.events,
+            &mut self.input_log,
+            self.cycles,
+        );
+        for (id, mapped) in self.devices.iter_mut().enumerate() {
+            ctx.select(id);
+            mapped.device.shutdown(&mut ctx);
+        }
+        self.input_log.flush();
+    }
+
+    fn tick_devices(&mut self) {
+        let mut ctx = Context::new(
+            &mut self.mem,
+            &mut self.events,
+            &mut self.input_log,
+            self.cycles,
+        );
+        for (id, mapped) in self.devices.iter_mut().enumerate() {
+            ctx.select(id);
+            mapped.device.tick(&mut ctx);
+        }
+        self.irq_pending |= ctx.irq;
+    }
+
+    fn run_events(&mut self) {
+        while let Some((id, token)) = self.events.pop_due(self.cycles) {
+            let (before, rest) = self.devices.split_at_mut(id);
+            let (mapped, after) = match rest.split_first_mut() {
+                Some(split) => split,
+                None => continue,
+            };
+            let mut ctx = Context::new(
+                &mut self.mem,
+                &mut self.events,
+                &mut self.input_log,
+                self.cycles,
+            );
+            ctx.select(id);
+            ctx.connect(before, after);
+            mapped.device.event(token, &mut ctx);
+            self.irq_pending |= ctx.irq;
+        }
+    }
+
+    /// Whether `address` is backed by memory or a mapped device.
+    fn mapped(&self, address: u16) -> bool {
+        return address < self.mem.size || self.devices.iter().any(|d| d.contains(address));
+    }
+
+    fn load(&mut self, address: u16) -> Result<u8, Exception> {
+        let mut ctx = Context::new(
+            &mut self.mem,
+            &mut self.events,
+            &mut self.input_log,
+            self.cycles,
+        );
+        for (id, mapped) in self.devices.iter_mut().enumerate() {
+            if mapped.contains(address) {
+                ctx.select(id);
+                let value = mapped.device.read(address - mapped.base, &mut ctx);
+                self.irq_pending |= ctx.irq;
+                self.memory_accessed(address, value, false);
+                return Ok(value);
+            }
+        }
+        if address >= self.mem.size {
+            return Err(Exception::SEG);
+        }
+        let value = self.mem.data[address as usize];
+        self.memory_accessed(address, value, false);
+        return Ok(value);
+    }
+
+    fn store(&mut self, address: u16, value: u8) -> Result<(), Exception> {
+        let mut ctx = Context::new(
+            &mut self.mem,
+            &mut self.events,
+            &mut self.input_log,
+            self.cycles,
+        );
+        for (id, mapped) in self.devices.iter_mut().enumerate() {
+            if mapped.contains(address) {
+                ctx.select(id);
+                mapped.device.write(address - mapped.base, value, &mut ctx);
+                self.irq_pending |= ctx.irq;
+                self.memory_accessed(address, value, true);
+                return Ok(());
+            }
+        }
+        if address >= self.mem.size {
+            return Err(Exception::SEG);
+        }
+        self.mem.data[address as usize] = value;
+        self.memory_accessed(address, value, true);
+        return Ok(());
+    }
+
+    fn memory_accessed(&mut self, address: u16, value: u8, write: bool) {
+        let hooks = if write {
+            &mut self.hooks.memory_write
+        } else {
+            &mut self.hooks.memory_read
+        };
+        if hooks.is_empty() {
+            return;
+        }
+        let access = MemoryAccess {
+            pc: self.regs.pc,
+            address,
+            value,
+        };
+        Hooks::memory(hooks, &access);
     }
 
     fn fetch(&self) -> u16 {
@@ -243,8 +854,7 @@ impl VirtualMachine {
         return (opcode_hi << 8) | opcode_lo;
     }
 
-    fn step(&mut self) -> Result<u16, Exception> {
-        let opcode = self.fetch();
+    fn execute(&mut self, opcode: u16) -> Result<u16, Exception> {
         if self.config.verbose {
             println!(
                 " [PC={:0>4X}] Executing opcode ({:0>4X})",
@@ -319,11 +929,8 @@ impl VirtualMachine {
                         } else {
                             self.regs.sp
                         };
-                        if address >= self.mem.size {
-                            return Err(Exception::SEG);
-                        }
                         let xh = self.regs.r[x as usize] & 0xFF00;
-                        self.regs.r[x as usize] = xh | self.mem.data[address as usize] as u16;
+                        self.regs.r[x as usize] = xh | self.load(address)? as u16;
                     }
                     Instruction::LDW => {
                         if !check_register_range(x, RegisterId::R7)
@@ -336,12 +943,14 @@ impl VirtualMachine {
                         } else {
                             self.regs.sp
                         };
-                        if address >= self.mem.size - 1 {
+                        // Fault before the first byte is touched.
+                        if address == u16::MAX || !self.mapped(address) || !self.mapped(address + 1)
+                        {
                             return Err(Exception::SEG);
                         }
-                        self.regs.r[x as usize] = ((self.mem.data[address as usize + 1] as u16)
-                            << 8)
-                            | (self.mem.data[address as usize] as u16);
+                        let lo = self.load(address)? as u16;
+                        let hi = self.load(address + 1)? as u16;
+                        self.regs.r[x as usize] = (hi << 8) | lo;
                     }
                     Instruction::MOV => {
                         if !check_register_range(x, RegisterId::C1)
@@ -368,6 +977,20 @@ impl VirtualMachine {
                             &mut self.regs.r[x as usize]
                         };
                         *reg_x = reg_y;
+                        if x == RegisterId::C0 as u16 {
+                            if let Some(host) = self.semihosting.as_mut() {
+                                host.call(
+                                    &mut self.regs,
+                                    &mut self.mem,
+                                    &mut self.input_log,
+                                    self.cycles,
+                                );
+                                if let Some(status) = host.exit_status() {
+                                    self.stop_reason = Some(StopReason::Exit { status });
+                                    self.should_run.store(false, Ordering::Relaxed);
+                                }
+                            }
+                        }
                     }
                     Instruction::LDI => {
                         if !check_register_range(x, RegisterId::R7) {
@@ -386,10 +1009,7 @@ impl VirtualMachine {
                         } else {
                             self.regs.sp
                         };
-                        if address >= self.mem.size {
-                            return Err(Exception::SEG);
-                        }
-                        self.mem.data[address as usize] = (self.regs.r[y as usize] & 0x00FF) as u8;
+                        self.store(address, (self.regs.r[y as usize] & 0x00FF) as u8)?;
                     }
                     Instruction::STW => {
                         if !check_register_range(x, RegisterId::SP)
@@ -402,18 +1022,21 @@ impl VirtualMachine {
                         } else {
                             self.regs.sp
                         };
-                        if address >= self.mem.size - 1 {
+                        // Fault before the first byte is written, so a word
+                        // store is never left half done.
+                        if address == u16::MAX || !self.mapped(address) || !self.mapped(address + 1)
+                        {
                             return Err(Exception::SEG);
                         }
-                        self.mem.data[address as usize + 1] = (self.regs.r[y as usize] >> 8) as u8;
-                        self.mem.data[address as usize] = (self.regs.r[y as usize] & 0x00FF) as u8;
+                        self.store(address, (self.regs.r[y as usize] & 0x00FF) as u8)?;
+                        self.store(address + 1, (self.regs.r[y as usize] >> 8) as u8)?;
                     }
                     Instruction::JMP => {
                         if !check_register_range(x, RegisterId::SP) {
                             return Err(Exception::IOP);
                         }
                         let address = self.regs.r[x as usize];
-                        if address % 2 != 0 {
+                        if !address.is_multiple_of(2) {
                             return Err(Exception::UNA);
                         }
                         self.regs.pc = address;
@@ -426,7 +1049,7 @@ impl VirtualMachine {
                             return Err(Exception::IOP);
                         }
                         let address = self.regs.r[x as usize];
-                        if address % 2 != 0 {
+                        if !address.is_multiple_of(2) {
                             return Err(Exception::UNA);
                         }
                         if self.regs.r[y as usize] == 0 {
@@ -459,7 +1082,7 @@ impl VirtualMachine {
                     }
                 }
             }
-            None => panic!("Failed to fetch next instruction"),
+            None => return Err(Exception::ILL),
         }
         return Ok(2);
     }
@@ -497,3 +1120,68 @@ fn decode_opcode(opcode: u16) -> Option<Instruction> {
         _ => None,
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn machine() -> VirtualMachine {
+        let mut vm = VirtualMachine::new(Configuration::default()).expect("a machine");
+        vm.reset();
+        return vm;
+    }
+
+    #[test]
+    fn run_for_executes_the_given_number_of_instructions() {
+        let mut vm = machine();
+        assert_eq!(vm.run_for(10), None);
+        assert_eq!(vm.state(&[]).cycles, 10);
+        assert_eq!(vm.registers().r[0], 0xDEAD);
+        assert_eq!(vm.registers().r[1], 0xBEEF);
+    }
+
+    #[test]
+    fn stepping_stops_once_the_machine_is_interrupted() {
+        let mut vm = machine();
+        assert_eq!(vm.step(), None);
+        vm.should_run.store(false, Ordering::Relaxed);
+        assert_eq!(vm.run_for(5), Some(StopReason::Interrupted));
+        assert_eq!(vm.state(&[]).cycles, 1);
+    }
+
+    /// A machine whose first instruction is `opcode`.
+    fn machine_executing(opcode: u16) -> VirtualMachine {
+        let mut vm = machine();
+        vm.mem.data[0..2].copy_from_slice(&opcode.to_le_bytes());
+        return vm;
+    }
+
+    #[test]
+    fn illegal_instructions_enter_the_exception_handler() {
+        for opcode in [0x1003, 0x9000, 0xF123] {
+            let mut vm = machine_executing(opcode);
+            vm.regs.c[1] = 0x0100;
+            vm.regs.sp = 0x0200;
+            assert_eq!(vm.step(), None);
+            assert_eq!(vm.regs.pc, 0x0100);
+            assert_eq!(vm.regs.fg, Exception::ILL.flag());
+            // The address of the faulting instruction is pushed.
+            assert_eq!(vm.regs.sp, 0x01FE);
+            assert_eq!(&vm.mem.data[0x01FE..0x0200], &[0x00, 0x00]);
+        }
+    }
+
+    #[test]
+    fn unhandled_illegal_instructions_stop_the_machine() {
+        let mut vm = machine_executing(0x9000);
+        let fault = StopReason::Fault {
+            exception: Exception::ILL,
+            pc: 0x0000,
+        };
+        assert_eq!(vm.step(), Some(fault));
+        assert_eq!(vm.regs.fg, Exception::ILL.flag());
+        assert!(!vm.should_run.load(Ordering::Relaxed));
+        assert_eq!(vm.step(), Some(fault));
+        assert_eq!(vm.state(&[]).cycles, 1);
+    }
+}
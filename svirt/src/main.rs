@@ -1,65 +1,278 @@
-use svirt::{Configuration, VirtualMachine};
 use std::env;
+use std::fs;
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+use std::process;
 use std::sync::atomic::Ordering;
+use svirt::devices::disk::DiskConfig;
+use svirt::devices::dma::DmaConfig;
+use svirt::devices::framebuffer::FramebufferConfig;
+use svirt::devices::keyboard::KeyboardConfig;
+use svirt::devices::rng::RngConfig;
+use svirt::devices::rtc::RtcConfig;
+use svirt::image::ImageFormat;
+use svirt::{Configuration, VirtualMachine};
+
+const USAGE: &str = "\
+Usage: svirt [OPTIONS] [-- ARGS...]
+
+Options:
+  --config=FILE          Load the machine configuration from a TOML file
+  --firmware=FILE        Firmware image to load (raw binary, Intel HEX or S-record)
+  --firmware-format=FMT  Force the firmware format (bin, ihex, srec)
+  --memory=SIZE          Memory size in bytes
+  --cps=N                Cycles per second
+  --start-address=ADDR   Initial program counter
+  --verbose              Print every executed opcode
+  --core=FILE            Write a core file to FILE on an unhandled fault
+  --no-core              Do not write a core file, even if configured
+  --trace-length=N       Instructions kept in the core file trace
+  --framebuffer[=ADDR]   Map the framebuffer at ADDR (default 0xFF00)
+  --fb-output=FILE       Write framebuffer snapshots to FILE (.ppm or .png)
+  --fb-interval=N        Take a framebuffer snapshot every N cycles
+  --fb-terminal          Draw framebuffer snapshots in the terminal
+  --keyboard[=ADDR]      Map the keyboard at ADDR (default 0xFF10)
+  --disk=FILE            Map a disk controller backed by the image FILE
+  --rtc[=ADDR]           Map the real-time clock at ADDR (default 0xFF30)
+  --rtc-epoch=SECS       Run the clock in deterministic mode starting at SECS
+  --rng[=ADDR]           Map the random number generator at ADDR (default 0xFF38)
+  --seed=N               Seed the random number generator
+  --dma[=ADDR]           Map the DMA controller at ADDR (default 0xFF40)
+  --dma-cycles=N         Cycles the DMA controller takes per word
+  --semihosting          Let firmware make host calls by writing to c0
+  --listen=ADDR          Accept JSON-RPC commands on unix:PATH or tcp:PORT
+  --paused               Start paused until a remote client resumes
+  --record=FILE          Log keyboard, console, clock and interrupt inputs to FILE
+  --replay=FILE          Replay the inputs logged with --record
+  --dump=FMT             Print the final machine state as text or json
+  --dump-memory=START-END
+                         Include memory from START to END in the dump
+  --dump-output=FILE     Write the final machine state to FILE
+  -h, --help             Print this help and exit
+
+Numbers can be written in decimal or in hexadecimal with a `0x` prefix.
+Options given on the command line override the configuration file.
+Arguments after `--` are passed to firmware through semihosting.";
+
+macro_rules! fail {
+    ($($arg:tt)*) => {
+        {
+            eprintln!("error: {}", format!($($arg)*));
+            eprintln!("Try `svirt --help` for more information.");
+            process::exit(2);
+        }
+    };
+}
+
+fn parse_number<T: TryFrom<u128>>(name: &str, val: &str) -> T {
+    let parsed = match val.strip_prefix("0x").or(val.strip_prefix("0X")) {
+        Some(hex) => u128::from_str_radix(hex, 16).ok(),
+        None => val.parse::<u128>().ok(),
+    };
+    match parsed.and_then(|v| T::try_from(v).ok()) {
+        Some(v) => return v,
+        None => fail!("`{}` is not a valid value for {}", val, name),
+    }
+}
+
+enum DumpFormat {
+    Text,
+    Json,
+}
 
-fn parse_args() -> Configuration {
+/// How the machine state is reported once it stops.
+struct DumpOptions {
+    format: DumpFormat,
+    memory: Vec<RangeInclusive<u16>>,
+    output: Option<String>,
+}
+
+fn parse_range(name: &str, val: &str) -> RangeInclusive<u16> {
+    match val.split_once('-') {
+        Some((start, end)) => {
+            let start: u16 = parse_number(name, start);
+            let end: u16 = parse_number(name, end);
+            if end < start {
+                fail!("`{}` is not a valid range for {}", val, name);
+            }
+            return start..=end;
+        }
+        None => fail!("`{}` is not a valid range for {}", val, name),
+    }
+}
+
+fn parse_args() -> (Configuration, DumpOptions) {
     let mut config = Configuration::default();
+    let mut dump = DumpOptions {
+        format: DumpFormat::Text,
+        memory: Vec::new(),
+        output: None,
+    };
+    let mut options = Vec::new();
+    let mut firmware_args = None;
 
-    for arg in env::args().into_iter() {
-        let mut name_val = arg.split("=");
-        let name = name_val.next().unwrap_or("");
-        let val = name_val.last().unwrap_or("");
-        match name {
-            "--memory" => {
-                if val.is_empty() {
-                    panic!("{} requires a value!", name);
-                }
-                let parsed = val.parse::<u16>();
-                match parsed {
-                    Ok(size) => config.memory_size = size,
-                    Err(e) => panic!("{} has an invalid value.\n{}", name, e),
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            firmware_args = Some(args.by_ref().collect::<Vec<String>>());
+            break;
+        }
+        let (name, val) = match arg.split_once('=') {
+            Some((name, val)) => (name.to_string(), Some(val.to_string())),
+            None => (arg.clone(), None),
+        };
+        match name.as_str() {
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                process::exit(0);
+            }
+            "--verbose" | "--fb-terminal" | "--semihosting" | "--no-core" | "--paused" => {
+                if val.is_some() {
+                    fail!("{} does not take a value", name);
                 }
+                options.push((name, String::new()));
+            }
+            "--framebuffer" | "--keyboard" | "--rtc" | "--rng" | "--dma" => {
+                options.push((name, val.unwrap_or_default()))
             }
-            "--cps" => {
-                if val.is_empty() {
-                    panic!("{} requires a value!", name);
+            "--config" | "--firmware" | "--firmware-format" | "--memory" | "--cps"
+            | "--start-address" | "--core" | "--trace-length" | "--listen" | "--record"
+            | "--replay" | "--fb-output" | "--fb-interval" | "--disk" | "--rtc-epoch"
+            | "--seed" | "--dma-cycles" | "--dump" | "--dump-memory" | "--dump-output" => match val
+            {
+                Some(val) if !val.is_empty() => options.push((name, val)),
+                _ => fail!("{} requires a value", name),
+            },
+            _ => fail!("unknown option `{}`", arg),
+        }
+    }
+
+    // The configuration file is applied first so that the remaining options
+    // can override it regardless of their order on the command line.
+    for (name, val) in options.iter() {
+        if name == "--config" {
+            if let Err(e) = config.load_file(val) {
+                fail!("{}", e);
+            }
+        }
+    }
+
+    for (name, val) in options.iter() {
+        match name.as_str() {
+            "--memory" => config.memory_size = parse_number(name, val),
+            "--cps" => config.cycles_per_second = parse_number(name, val),
+            "--start-address" => config.initial_pc = parse_number(name, val),
+            "--firmware" => config.firmware_file = val.clone(),
+            "--firmware-format" => match ImageFormat::from_name(val) {
+                Some(format) => config.firmware_format = Some(format),
+                None => fail!("unknown firmware format `{}`", val),
+            },
+            "--verbose" => config.verbose = true,
+            "--core" => config.core_file = Some(PathBuf::from(val)),
+            "--no-core" => config.core_file = None,
+            "--trace-length" => config.trace_length = parse_number(name, val),
+            "--semihosting" => config.semihosting = true,
+            "--listen" => config.listen = Some(val.clone()),
+            "--paused" => config.paused = true,
+            "--record" => config.record = Some(val.clone()),
+            "--replay" => config.replay = Some(val.clone()),
+            "--framebuffer" => {
+                let fb = config
+                    .framebuffer
+                    .get_or_insert_with(FramebufferConfig::default);
+                if !val.is_empty() {
+                    fb.base = parse_number(name, val);
                 }
-                let parsed = val.parse::<u128>();
-                match parsed {
-                    Ok(cps) => config.cycles_per_second = cps,
-                    Err(e) => panic!("{} has an invalid value.\n{}", name, e),
+            }
+            "--keyboard" => {
+                let kbd = config.keyboard.get_or_insert_with(KeyboardConfig::default);
+                if !val.is_empty() {
+                    kbd.base = parse_number(name, val);
                 }
             }
-            "--start-address" => {
-                if val.is_empty() {
-                    panic!("{} requires a value!", name);
+            "--disk" => {
+                let disk = config.disk.get_or_insert_with(DiskConfig::default);
+                disk.image = val.clone();
+            }
+            "--rtc" => {
+                let rtc = config.rtc.get_or_insert_with(RtcConfig::default);
+                if !val.is_empty() {
+                    rtc.base = parse_number(name, val);
                 }
-                let parsed = val.parse::<u16>();
-                match parsed {
-                    Ok(address) => config.initial_pc = address,
-                    Err(e) => panic!("{} has an invalid value.\n{}", name, e),
+            }
+            "--rtc-epoch" => {
+                let rtc = config.rtc.get_or_insert_with(RtcConfig::default);
+                rtc.epoch = Some(parse_number(name, val));
+            }
+            "--rng" => {
+                let rng = config.rng.get_or_insert_with(RngConfig::default);
+                if !val.is_empty() {
+                    rng.base = parse_number(name, val);
                 }
             }
-            "--firmware" => {
-                if val.is_empty() {
-                    panic!("{} requires a value!", name);
+            "--seed" => {
+                let rng = config.rng.get_or_insert_with(RngConfig::default);
+                rng.seed = Some(parse_number(name, val));
+            }
+            "--dma" => {
+                let dma = config.dma.get_or_insert_with(DmaConfig::default);
+                if !val.is_empty() {
+                    dma.base = parse_number(name, val);
                 }
-                config.firmware_file = val.to_string();
             }
-            "--verbose" => {
-                config.verbose = true;
+            "--dma-cycles" => {
+                let dma = config.dma.get_or_insert_with(DmaConfig::default);
+                dma.cycles_per_word = parse_number(name, val);
+            }
+            "--fb-output" => {
+                let fb = config
+                    .framebuffer
+                    .get_or_insert_with(FramebufferConfig::default);
+                fb.output = val.clone();
             }
+            "--fb-interval" => {
+                let fb = config
+                    .framebuffer
+                    .get_or_insert_with(FramebufferConfig::default);
+                fb.interval = parse_number(name, val);
+            }
+            "--fb-terminal" => {
+                let fb = config
+                    .framebuffer
+                    .get_or_insert_with(FramebufferConfig::default);
+                fb.terminal = true;
+            }
+            "--dump" => match val.as_str() {
+                "text" => dump.format = DumpFormat::Text,
+                "json" => dump.format = DumpFormat::Json,
+                _ => fail!("unknown dump format `{}`", val),
+            },
+            "--dump-memory" => dump.memory.push(parse_range(name, val)),
+            "--dump-output" => dump.output = Some(val.clone()),
             _ => {}
         }
     }
 
-    config.dump_to_stdout();
-    return config;
+    if let Some(args) = firmware_args {
+        config.args = args;
+    }
+
+    if let Err(e) = config.validate() {
+        fail!("{}", e);
+    }
+    // Keep stdout clean for scripts reading a JSON dump from it.
+    if matches!(dump.format, DumpFormat::Text) {
+        config.dump_to_stdout();
+    }
+    return (config, dump);
 }
 
 fn main() {
-    let config = parse_args();
-    let mut vm = VirtualMachine::new(config);
+    let (config, dump) = parse_args();
+    let mut vm = match VirtualMachine::new(config) {
+        Ok(vm) => vm,
+        Err(e) => fail!("{}", e),
+    };
 
     let should_run = vm.should_run.clone();
     _ = ctrlc::set_handler(move || {
@@ -68,5 +281,23 @@ fn main() {
 
     vm.reset();
     vm.run();
-    vm.dump_to_stdout();
+    vm.shutdown();
+
+    let state = vm.state(&dump.memory);
+    let report = match dump.format {
+        DumpFormat::Text => state.to_string(),
+        DumpFormat::Json => state.to_json() + "\n",
+    };
+    match &dump.output {
+        Some(path) => {
+            if let Err(e) = fs::write(path, report) {
+                eprintln!("error: cannot write `{}`: {}", path, e);
+            }
+        }
+        None => print!("{}", report),
+    }
+
+    if let Some(status) = vm.exit_status() {
+        process::exit(status as i32);
+    }
 }
@@ -0,0 +1,459 @@
+use crate::image::ImageFormat;
+use crate::state::{parse_hex, to_hex};
+use crate::{Firmware, VirtualMachine};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::UnixListener;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::Duration;
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const MACHINE_ERROR: i64 = -32000;
+
+/// The most instructions a single `step` call may execute, so that a call
+/// cannot hold up the others for long.
+const MAX_STEPS: u64 = 1_000_000;
+
+// How long a paused machine waits for a call before checking whether it
+// should still run.
+const PAUSED_POLL: Duration = Duration::from_millis(10);
+
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        return Self {
+            code,
+            message: message.into(),
+        };
+    }
+}
+
+/// A request forwarded from a client connection to the machine, along with
+/// the channel its result is sent back through.
+struct Call {
+    method: String,
+    params: Value,
+    reply: Sender<Result<Value, RpcError>>,
+}
+
+/// A JSON-RPC 2.0 server on a Unix socket or a localhost TCP port. Every
+/// connection is served by its own thread, one request per line, and the
+/// calls are executed by the machine between two cycles.
+pub(crate) struct Remote {
+    calls: Receiver<Call>,
+    socket_path: Option<String>,
+    pub paused: bool,
+}
+
+impl Remote {
+    /// Listens on `unix:PATH` or `tcp:PORT`.
+    pub fn listen(address: &str, paused: bool) -> Result<Self, String> {
+        let (sender, calls) = mpsc::channel();
+        let mut socket_path = None;
+        if let Some(path) = address.strip_prefix("unix:") {
+            // A socket left behind by an earlier run would make bind fail,
+            // but anything else at that path is not ours to remove.
+            match fs::symlink_metadata(path) {
+                Ok(meta) if meta.file_type().is_socket() => _ = fs::remove_file(path),
+                Ok(_) => return Err(format!("`{}` exists and is not a socket", path)),
+                Err(_) => {}
+            }
+            let listener = match UnixListener::bind(path) {
+                Ok(listener) => listener,
+                Err(e) => return Err(format!("cannot listen on `{}`: {}", path, e)),
+            };
+            socket_path = Some(path.to_string());
+            thread::spawn(move || {
+                for stream in listener.incoming().flatten() {
+                    if let Ok(reader) = stream.try_clone() {
+                        let sender = sender.clone();
+                        thread::spawn(move || serve(reader, stream, sender));
+                    }
+                }
+            });
+        } else if let Some(port) = address.strip_prefix("tcp:") {
+            let port: u16 = match port.parse() {
+                Ok(port) => port,
+                Err(_) => return Err(format!("invalid port `{}`", port)),
+            };
+            let listener = match TcpListener::bind(("127.0.0.1", port)) {
+                Ok(listener) => listener,
+                Err(e) => return Err(format!("cannot listen on port {}: {}", port, e)),
+            };
+            thread::spawn(move || {
+                for stream in listener.incoming().flatten() {
+                    if let Ok(reader) = stream.try_clone() {
+                        let sender = sender.clone();
+                        thread::spawn(move || serve(reader, stream, sender));
+                    }
+                }
+            });
+        } else {
+            return Err(format!(
+                "invalid address `{}`, expected unix:PATH or tcp:PORT",
+                address
+            ));
+        }
+        return Ok(Self {
+            calls,
+            socket_path,
+            paused,
+        });
+    }
+
+    /// Collects the calls waiting to be executed. While paused, waits a
+    /// little for the first one so the run loop does not spin.
+    fn pending(&self) -> Vec<Call> {
+        let mut calls = Vec::new();
+        if self.paused {
+            match self.calls.recv_timeout(PAUSED_POLL) {
+                Ok(call) => calls.push(call),
+                Err(RecvTimeoutError::Timeout) => return calls,
+                Err(RecvTimeoutError::Disconnected) => return calls,
+            }
+        }
+        while let Ok(call) = self.calls.try_recv() {
+            calls.push(call);
+        }
+        return calls;
+    }
+}
+
+impl Drop for Remote {
+    fn drop(&mut self) {
+        if let Some(path) = &self.socket_path {
+            _ = fs::remove_file(path);
+        }
+    }
+}
+
+fn serve(reader: impl Read, mut writer: impl Write, calls: Sender<Call>) {
+    for line in BufReader::new(reader).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => return,
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        let (id, result) = match serde_json::from_str::<Value>(&line) {
+            Ok(request) => {
+                let id = request.get("id").cloned();
+                (id, forward(request, &calls))
+            }
+            Err(e) => (
+                Some(Value::Null),
+                Err(RpcError::new(PARSE_ERROR, e.to_string())),
+            ),
+        };
+        // Requests without an id are notifications and get no response.
+        let id = match id {
+            Some(id) => id,
+            None => continue,
+        };
+        let response = match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err(e) => json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": { "code": e.code, "message": e.message },
+            }),
+        };
+        if writeln!(writer, "{}", response).is_err() {
+            return;
+        }
+    }
+}
+
+fn forward(request: Value, calls: &Sender<Call>) -> Result<Value, RpcError> {
+    let method = match request.get("method").and_then(Value::as_str) {
+        Some(method) => method.to_string(),
+        None => return Err(RpcError::new(INVALID_REQUEST, "missing method")),
+    };
+    let params = request.get("params").cloned().unwrap_or(Value::Null);
+    let (reply, result) = mpsc::channel();
+    let call = Call {
+        method,
+        params,
+        reply,
+    };
+    if calls.send(call).is_err() {
+        return Err(RpcError::new(MACHINE_ERROR, "machine stopped"));
+    }
+    match result.recv() {
+        Ok(result) => return result,
+        Err(_) => return Err(RpcError::new(MACHINE_ERROR, "machine stopped")),
+    }
+}
+
+fn params<T: DeserializeOwned>(params: Value) -> Result<T, RpcError> {
+    let params = if params.is_null() { json!({}) } else { params };
+    return serde_json::from_value(params)
+        .map_err(|e| RpcError::new(INVALID_PARAMS, e.to_string()));
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct StepParams {
+    #[serde(default = "one")]
+    count: u64,
+}
+
+fn one() -> u64 {
+    return 1;
+}
+
+/// Registers to overwrite; the ones left out keep their value.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RegisterParams {
+    r: Option<Vec<Option<u16>>>,
+    c: Option<Vec<Option<u16>>>,
+    sp: Option<u16>,
+    fg: Option<u16>,
+    pc: Option<u16>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ReadMemoryParams {
+    address: u16,
+    length: usize,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct WriteMemoryParams {
+    address: u16,
+    /// Hex digits, two per byte.
+    data: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LoadImageParams {
+    path: String,
+    format: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DumpStateParams {
+    #[serde(default)]
+    memory: Vec<(u16, u16)>,
+}
+
+impl VirtualMachine {
+    /// Executes the calls made by remote clients. Returns true while the
+    /// machine is paused.
+    pub(crate) fn serve_remote(&mut self) -> bool {
+        let calls = match &self.remote {
+            Some(remote) => remote.pending(),
+            None => return false,
+        };
+        for call in calls {
+            let result = self.execute_call(&call.method, call.params);
+            _ = call.reply.send(result);
+        }
+        return self.remote.as_ref().is_some_and(|remote| remote.paused);
+    }
+
+    fn execute_call(&mut self, method: &str, args: Value) -> Result<Value, RpcError> {
+        match method {
+            "pause" | "resume" => {
+                if method == "resume" {
+                    self.check_runnable()?;
+                }
+                if let Some(remote) = self.remote.as_mut() {
+                    remote.paused = method == "pause";
+                }
+                return Ok(Value::Null);
+            }
+            "step" => {
+                let args: StepParams = params(args)?;
+                if !self.remote.as_ref().is_some_and(|remote| remote.paused) {
+                    return Err(RpcError::new(MACHINE_ERROR, "machine is running"));
+                }
+                if args.count > MAX_STEPS {
+                    let message = format!("cannot step more than {} instructions", MAX_STEPS);
+                    return Err(RpcError::new(INVALID_PARAMS, message));
+                }
+                self.check_runnable()?;
+                self.run_for(args.count);
+                return Ok(to_value(&self.state(&[])));
+            }
+            "get_registers" => return Ok(to_value(&self.regs)),
+            "set_registers" => {
+                let args: RegisterParams = params(args)?;
+                if args.r.as_ref().is_some_and(|r| r.len() > self.regs.r.len())
+                    || args.c.as_ref().is_some_and(|c| c.len() > self.regs.c.len())
+                {
+                    return Err(RpcError::new(INVALID_PARAMS, "too many registers"));
+                }
+                for (i, value) in args.r.unwrap_or_default().into_iter().enumerate() {
+                    self.regs.r[i] = value.unwrap_or(self.regs.r[i]);
+                }
+                for (i, value) in args.c.unwrap_or_default().into_iter().enumerate() {
+                    self.regs.c[i] = value.unwrap_or(self.regs.c[i]);
+                }
+                self.regs.sp = args.sp.unwrap_or(self.regs.sp);
+                self.regs.fg = args.fg.unwrap_or(self.regs.fg);
+                self.regs.pc = args.pc.unwrap_or(self.regs.pc);
+                return Ok(to_value(&self.regs));
+            }
+            "read_memory" => {
+                let args: ReadMemoryParams = params(args)?;
+                let start = args.address as usize;
+                let end = match start.checked_add(args.length) {
+                    Some(end) if end <= self.mem.data.len() => end,
+                    _ => return Err(RpcError::new(INVALID_PARAMS, "range outside of memory")),
+                };
+                let data = to_hex(&self.mem.data[start..end]);
+                return Ok(json!({ "address": args.address, "data": data }));
+            }
+            "write_memory" => {
+                let args: WriteMemoryParams = params(args)?;
+                let data = match parse_hex(&args.data) {
+                    Some(data) => data,
+                    None => return Err(RpcError::new(INVALID_PARAMS, "invalid hex data")),
+                };
+                let start = args.address as usize;
+                let end = start + data.len();
+                if end > self.mem.data.len() {
+                    return Err(RpcError::new(INVALID_PARAMS, "range outside of memory"));
+                }
+                self.mem.data[start..end].copy_from_slice(&data);
+                return Ok(Value::Null);
+            }
+            "load_image" => {
+                let args: LoadImageParams = params(args)?;
+                let format = match args.format {
+                    Some(name) => match ImageFormat::from_name(&name) {
+                        Some(format) => Some(format),
+                        None => {
+                            return Err(RpcError::new(
+                                INVALID_PARAMS,
+                                format!("unknown firmware format `{}`", name),
+                            ))
+                        }
+                    },
+                    None => None,
+                };
+                let firmware = match Firmware::load(&args.path, format) {
+                    Ok(firmware) => firmware,
+                    Err(e) => return Err(RpcError::new(MACHINE_ERROR, e)),
+                };
+                if !firmware.fits(self.config.initial_pc, self.mem.size) {
+                    return Err(RpcError::new(MACHINE_ERROR, "image does not fit in memory"));
+                }
+                self.check_resettable()?;
+                self.firmware = firmware;
+                self.reset();
+                return Ok(Value::Null);
+            }
+            "reset" => {
+                self.check_resettable()?;
+                self.reset();
+                return Ok(Value::Null);
+            }
+            "dump_state" => {
+                let args: DumpStateParams = params(args)?;
+                let ranges: Vec<_> = args.memory.iter().map(|(s, e)| *s..=*e).collect();
+                return Ok(to_value(&self.state(&ranges)));
+            }
+            _ => {
+                return Err(RpcError::new(
+                    METHOD_NOT_FOUND,
+                    format!("unknown method `{}`", method),
+                ))
+            }
+        }
+    }
+}
+
+impl VirtualMachine {
+    /// Fails if the machine stopped on a fault and has to be reset first.
+    fn check_runnable(&self) -> Result<(), RpcError> {
+        match &self.stop_reason {
+            Some(reason) => {
+                let message = format!("machine stopped: {}; reset it first", reason);
+                return Err(RpcError::new(MACHINE_ERROR, message));
+            }
+            None => return Ok(()),
+        }
+    }
+
+    /// Fails while inputs are recorded or replayed: the log is keyed by
+    /// cycle, and a reset would start counting cycles again.
+    fn check_resettable(&self) -> Result<(), RpcError> {
+        if self.input_log.active() {
+            let message = "cannot reset while recording or replaying inputs";
+            return Err(RpcError::new(MACHINE_ERROR, message));
+        }
+        return Ok(());
+    }
+}
+
+fn to_value(value: &impl serde::Serialize) -> Value {
+    return serde_json::to_value(value).expect("value is serializable");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Configuration;
+    use std::env;
+    use std::process;
+
+    /// The message of the error `method` fails with, if it fails.
+    fn error(vm: &mut VirtualMachine, method: &str) -> Option<String> {
+        return vm
+            .execute_call(method, Value::Null)
+            .err()
+            .map(|e| e.message);
+    }
+
+    #[test]
+    fn dump_state_returns_the_machine_state() {
+        let mut vm = VirtualMachine::new(Configuration::default()).expect("a machine");
+        vm.reset();
+        let params = json!({ "memory": [[0, 1]] });
+        let state = match vm.execute_call("dump_state", params) {
+            Ok(state) => state,
+            Err(e) => panic!("dump_state failed: {}", e.message),
+        };
+        assert_eq!(state["cycles"], 0);
+        assert_eq!(state["memory"][0]["data"], "de40");
+        assert_eq!(
+            error(&mut vm, "snapshot"),
+            Some("unknown method `snapshot`".to_string())
+        );
+    }
+
+    #[test]
+    fn cannot_reset_while_recording() {
+        let log = env::temp_dir().join(format!("svirt-remote-{}.log", process::id()));
+        let config = Configuration {
+            record: Some(log.to_string_lossy().to_string()),
+            ..Configuration::default()
+        };
+        let mut vm = VirtualMachine::new(config).expect("a machine");
+        vm.reset();
+        let message = "cannot reset while recording or replaying inputs".to_string();
+        assert_eq!(error(&mut vm, "reset"), Some(message));
+        _ = fs::remove_file(log);
+    }
+}
@@ -0,0 +1,167 @@
+use crate::state::{parse_hex, to_hex};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+
+/// An input the machine cannot reproduce on its own.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub(crate) enum Input {
+    /// Bytes returned by a semihosting read from stdin, as hex digits.
+    Console { data: String },
+    /// A key code received by the keyboard.
+    Key { code: u8 },
+    /// The host time latched by the real-time clock.
+    Clock { millis: u64 },
+    /// The seed the random number generator was started with.
+    Seed { seed: u64 },
+    /// An interrupt taken by the processor.
+    Interrupt,
+}
+
+impl Input {
+    pub fn console(data: &[u8]) -> Self {
+        return Input::Console { data: to_hex(data) };
+    }
+
+    pub fn console_data(&self) -> Option<Vec<u8>> {
+        match self {
+            Input::Console { data } => return parse_hex(data),
+            _ => return None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    cycle: u64,
+    #[serde(flatten)]
+    input: Input,
+}
+
+enum Mode {
+    Off,
+    Record(BufWriter<File>),
+    Replay(VecDeque<Entry>),
+}
+
+/// A log of every nondeterministic input, one JSON object per line keyed by
+/// the cycle it arrived at. Recording a session and replaying its log makes
+/// a later run see exactly the same inputs at the same cycles.
+pub(crate) struct InputLog {
+    mode: Mode,
+    diverged: bool,
+}
+
+impl InputLog {
+    pub fn off() -> Self {
+        return Self {
+            mode: Mode::Off,
+            diverged: false,
+        };
+    }
+
+    pub fn record(path: &str) -> Result<Self, String> {
+        let file = match File::create(path) {
+            Ok(file) => file,
+            Err(e) => return Err(format!("cannot create `{}`: {}", path, e)),
+        };
+        return Ok(Self {
+            mode: Mode::Record(BufWriter::new(file)),
+            diverged: false,
+        });
+    }
+
+    pub fn replay(path: &str) -> Result<Self, String> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) => return Err(format!("cannot read `{}`: {}", path, e)),
+        };
+        let mut entries = VecDeque::new();
+        for (i, line) in text.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str::<Entry>(line) {
+                Ok(entry) => entries.push_back(entry),
+                Err(e) => return Err(format!("{}:{}: {}", path, i + 1, e)),
+            }
+        }
+        return Ok(Self {
+            mode: Mode::Replay(entries),
+            diverged: false,
+        });
+    }
+
+    /// Whether inputs come from the log rather than from the host.
+    pub fn replaying(&self) -> bool {
+        return matches!(self.mode, Mode::Replay(_));
+    }
+
+    /// Whether inputs are being recorded or replayed.
+    pub fn active(&self) -> bool {
+        return !matches!(self.mode, Mode::Off);
+    }
+
+    /// Appends `input` to the log when recording.
+    pub fn push(&mut self, cycle: u64, input: Input) {
+        if let Mode::Record(writer) = &mut self.mode {
+            let entry = Entry { cycle, input };
+            let line = serde_json::to_string(&entry).expect("input is serializable");
+            _ = writeln!(writer, "{}", line);
+        }
+    }
+
+    /// Takes the next logged input if it arrived at `cycle` and is of the
+    /// kind accepted by `kind`.
+    pub fn take(&mut self, cycle: u64, kind: fn(&Input) -> bool) -> Option<Input> {
+        let entries = match &mut self.mode {
+            Mode::Replay(entries) => entries,
+            _ => return None,
+        };
+        match entries.front() {
+            Some(entry) if entry.cycle == cycle && kind(&entry.input) => {
+                return entries.pop_front().map(|entry| entry.input);
+            }
+            _ => return None,
+        }
+    }
+
+    /// Drops logged inputs the machine went past without using, which
+    /// means the replay no longer follows the recorded run.
+    pub fn check(&mut self, cycle: u64) {
+        let entries = match &mut self.mode {
+            Mode::Replay(entries) => entries,
+            _ => return,
+        };
+        let mut missed = None;
+        while entries.front().is_some_and(|entry| entry.cycle < cycle) {
+            missed = missed.or(entries.pop_front());
+        }
+        if let Some(entry) = missed {
+            self.diverge(entry.cycle, format!("{:?} was not used", entry.input));
+        }
+    }
+
+    /// Takes a logged input the machine produces by itself, such as an
+    /// interrupt, warning if the recorded run did not have it.
+    pub fn expect(&mut self, cycle: u64, kind: fn(&Input) -> bool) {
+        if self.replaying() && self.take(cycle, kind).is_none() {
+            self.diverge(cycle, "unexpected input".to_string());
+        }
+    }
+
+    fn diverge(&mut self, cycle: u64, reason: String) {
+        if !self.diverged {
+            eprintln!(" [WARN] Replay diverged at cycle {}: {}", cycle, reason);
+            self.diverged = true;
+        }
+    }
+
+    pub fn flush(&mut self) {
+        if let Mode::Record(writer) = &mut self.mode {
+            _ = writer.flush();
+        }
+    }
+}
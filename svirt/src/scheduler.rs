@@ -0,0 +1,91 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+struct Event {
+    cycle: u64,
+    // Breaks ties so that events due on the same cycle fire in the order
+    // they were scheduled.
+    sequence: u64,
+    device: usize,
+    token: u32,
+}
+
+/// A queue of device callbacks indexed by the cycle counter. Timing device
+/// work in cycles rather than wall-clock time keeps it deterministic no
+/// matter how fast the machine is run.
+#[derive(Default)]
+pub(crate) struct Scheduler {
+    queue: BinaryHeap<Reverse<Event>>,
+    sequence: u64,
+}
+
+impl Scheduler {
+    pub fn schedule(&mut self, cycle: u64, device: usize, token: u32) {
+        self.queue.push(Reverse(Event {
+            cycle,
+            sequence: self.sequence,
+            device,
+            token,
+        }));
+        self.sequence += 1;
+    }
+
+    pub fn clear(&mut self) {
+        self.queue.clear();
+    }
+
+    /// Pops the next event that is due at `cycle`, returning the device it
+    /// belongs to and its token.
+    pub fn pop_due(&mut self, cycle: u64) -> Option<(usize, u32)> {
+        match self.queue.peek() {
+            Some(Reverse(event)) if event.cycle <= cycle => {}
+            _ => return None,
+        }
+        let Reverse(event) = self.queue.pop()?;
+        return Some((event.device, event.token));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Pops every event due at `cycle`.
+    fn drain(scheduler: &mut Scheduler, cycle: u64) -> Vec<(usize, u32)> {
+        return std::iter::from_fn(|| scheduler.pop_due(cycle)).collect();
+    }
+
+    #[test]
+    fn events_on_the_same_cycle_fire_in_scheduling_order() {
+        let mut scheduler = Scheduler::default();
+        scheduler.schedule(10, 2, 0);
+        scheduler.schedule(10, 0, 1);
+        scheduler.schedule(10, 1, 2);
+        scheduler.schedule(10, 0, 0);
+        assert_eq!(
+            drain(&mut scheduler, 10),
+            vec![(2, 0), (0, 1), (1, 2), (0, 0)]
+        );
+    }
+
+    #[test]
+    fn events_fire_by_cycle_then_scheduling_order() {
+        let mut scheduler = Scheduler::default();
+        scheduler.schedule(30, 0, 0);
+        scheduler.schedule(20, 1, 0);
+        scheduler.schedule(20, 0, 1);
+        scheduler.schedule(5, 2, 0);
+        assert_eq!(drain(&mut scheduler, 4), vec![]);
+        assert_eq!(drain(&mut scheduler, 25), vec![(2, 0), (1, 0), (0, 1)]);
+        assert_eq!(drain(&mut scheduler, 30), vec![(0, 0)]);
+    }
+
+    #[test]
+    fn clearing_drops_pending_events() {
+        let mut scheduler = Scheduler::default();
+        scheduler.schedule(1, 0, 0);
+        scheduler.clear();
+        assert_eq!(scheduler.pop_due(u64::MAX), None);
+    }
+}
@@ -0,0 +1,196 @@
+use crate::replay::{Input, InputLog};
+use crate::{Memory, Registers};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+
+const SYS_EXIT: u16 = 0x01;
+const SYS_PUTC: u16 = 0x02;
+const SYS_PUTS: u16 = 0x03;
+const SYS_OPEN: u16 = 0x04;
+const SYS_CLOSE: u16 = 0x05;
+const SYS_READ: u16 = 0x06;
+const SYS_WRITE: u16 = 0x07;
+const SYS_ARGC: u16 = 0x08;
+const SYS_ARGV: u16 = 0x09;
+
+const MODE_READ: u16 = 0;
+const MODE_WRITE: u16 = 1;
+const MODE_APPEND: u16 = 2;
+
+const FAILURE: u16 = 0xFFFF;
+
+// Handles 0 to 2 are the host's stdin, stdout and stderr.
+const FIRST_FILE_HANDLE: usize = 3;
+
+/// Host calls made by firmware writing an operation number into c0. The
+/// arguments are passed in r1 to r3 and the result is returned in r0.
+///
+/// | c0   | Call  | Arguments                        | Result            |
+/// | ---- | ----- | -------------------------------- | ----------------- |
+/// | 0x01 | EXIT  | r1 status                        | --                |
+/// | 0x02 | PUTC  | r1 character                     | --                |
+/// | 0x03 | PUTS  | r1 string                        | --                |
+/// | 0x04 | OPEN  | r1 path, r2 mode (0 r, 1 w, 2 a) | handle            |
+/// | 0x05 | CLOSE | r1 handle                        | 0                 |
+/// | 0x06 | READ  | r1 handle, r2 buffer, r3 length  | bytes read        |
+/// | 0x07 | WRITE | r1 handle, r2 buffer, r3 length  | bytes written     |
+/// | 0x08 | ARGC  | --                               | argument count    |
+/// | 0x09 | ARGV  | r1 index, r2 buffer, r3 size     | argument length   |
+///
+/// Strings are NUL terminated. Failed calls return 0xFFFF.
+pub(crate) struct Semihosting {
+    args: Vec<String>,
+    files: Vec<Option<File>>,
+    exit_status: Option<u16>,
+}
+
+impl Semihosting {
+    pub fn new(args: Vec<String>) -> Self {
+        return Self {
+            args,
+            files: Vec::new(),
+            exit_status: None,
+        };
+    }
+
+    pub fn exit_status(&self) -> Option<u16> {
+        return self.exit_status;
+    }
+
+    pub fn call(&mut self, regs: &mut Registers, mem: &mut Memory, log: &mut InputLog, cycle: u64) {
+        let (r1, r2, r3) = (regs.r[1], regs.r[2], regs.r[3]);
+        let result = match regs.c[0] {
+            SYS_EXIT => {
+                self.exit_status = Some(r1);
+                return;
+            }
+            SYS_PUTC => {
+                print!("{}", r1 as u8 as char);
+                _ = io::stdout().flush();
+                return;
+            }
+            SYS_PUTS => {
+                match read_string(mem, r1) {
+                    Some(s) => print!("{}", s),
+                    None => return,
+                }
+                _ = io::stdout().flush();
+                return;
+            }
+            SYS_OPEN => self.open(mem, r1, r2),
+            SYS_CLOSE => self.close(r1),
+            SYS_READ if r1 == 0 => read_console(mem, r2, r3, log, cycle),
+            SYS_READ => self.read(mem, r1, r2, r3),
+            SYS_WRITE => self.write(mem, r1, r2, r3),
+            SYS_ARGC => Some(self.args.len() as u16),
+            SYS_ARGV => self.argv(mem, r1, r2, r3),
+            _ => None,
+        };
+        regs.r[0] = result.unwrap_or(FAILURE);
+    }
+
+    fn open(&mut self, mem: &Memory, path: u16, mode: u16) -> Option<u16> {
+        let path = read_string(mem, path)?;
+        let mut options = OpenOptions::new();
+        match mode {
+            MODE_READ => options.read(true),
+            MODE_WRITE => options.write(true).create(true).truncate(true),
+            MODE_APPEND => options.append(true).create(true),
+            _ => return None,
+        };
+        let file = options.open(path).ok()?;
+        let index = match self.files.iter().position(|f| f.is_none()) {
+            Some(index) => index,
+            None => {
+                self.files.push(None);
+                self.files.len() - 1
+            }
+        };
+        self.files[index] = Some(file);
+        return u16::try_from(index + FIRST_FILE_HANDLE).ok();
+    }
+
+    fn close(&mut self, handle: u16) -> Option<u16> {
+        let index = (handle as usize).checked_sub(FIRST_FILE_HANDLE)?;
+        self.files.get_mut(index)?.take()?;
+        return Some(0);
+    }
+
+    fn read(&mut self, mem: &mut Memory, handle: u16, buffer: u16, len: u16) -> Option<u16> {
+        let buffer = checked_range(mem, buffer, len)?;
+        let data = &mut mem.data[buffer];
+        let read = match handle {
+            0..=2 => return None,
+            _ => self.file(handle)?.read(data).ok()?,
+        };
+        return Some(read as u16);
+    }
+
+    fn write(&mut self, mem: &Memory, handle: u16, buffer: u16, len: u16) -> Option<u16> {
+        let buffer = checked_range(mem, buffer, len)?;
+        let data = &mem.data[buffer];
+        match handle {
+            0 => return None,
+            1 => io::stdout()
+                .write_all(data)
+                .and_then(|_| io::stdout().flush())
+                .ok()?,
+            2 => io::stderr().write_all(data).ok()?,
+            _ => self.file(handle)?.write_all(data).ok()?,
+        }
+        return Some(len);
+    }
+
+    fn argv(&self, mem: &mut Memory, index: u16, buffer: u16, size: u16) -> Option<u16> {
+        let arg = self.args.get(index as usize)?.as_bytes();
+        if arg.len() >= size as usize {
+            return None;
+        }
+        let buffer = checked_range(mem, buffer, arg.len() as u16 + 1)?;
+        let data = &mut mem.data[buffer];
+        data[..arg.len()].copy_from_slice(arg);
+        data[arg.len()] = 0;
+        return Some(arg.len() as u16);
+    }
+
+    fn file(&mut self, handle: u16) -> Option<&mut File> {
+        let index = (handle as usize).checked_sub(FIRST_FILE_HANDLE)?;
+        return self.files.get_mut(index)?.as_mut();
+    }
+}
+
+/// Reads from stdin, or from the input log when replaying a session.
+fn read_console(
+    mem: &mut Memory,
+    buffer: u16,
+    len: u16,
+    log: &mut InputLog,
+    cycle: u64,
+) -> Option<u16> {
+    let buffer = checked_range(mem, buffer, len)?;
+    let data = &mut mem.data[buffer];
+    if log.replaying() {
+        let input = log.take(cycle, |input| matches!(input, Input::Console { .. }))?;
+        let logged = input.console_data()?;
+        let read = logged.len().min(data.len());
+        data[..read].copy_from_slice(&logged[..read]);
+        return Some(read as u16);
+    }
+    let read = io::stdin().read(data).ok()?;
+    log.push(cycle, Input::console(&data[..read]));
+    return Some(read as u16);
+}
+
+fn checked_range(mem: &Memory, start: u16, len: u16) -> Option<std::ops::Range<usize>> {
+    let end = start as usize + len as usize;
+    if end > mem.size as usize {
+        return None;
+    }
+    return Some(start as usize..end);
+}
+
+fn read_string(mem: &Memory, address: u16) -> Option<String> {
+    let tail = mem.data.get(address as usize..)?;
+    let len = tail.iter().position(|b| *b == 0)?;
+    return Some(String::from_utf8_lossy(&tail[..len]).to_string());
+}
@@ -0,0 +1,176 @@
+use crate::{Exception, Registers};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+// Names of the fg bits with an architectural meaning; the others are free
+// for firmware and are reported as F<bit>.
+const FLAG_NAMES: [(u16, &str); 6] = [
+    (15, "IOP"),
+    (14, "SEG"),
+    (13, "UNA"),
+    (12, "ILL"),
+    (11, "IRQ"),
+    (10, "IE"),
+];
+
+/// Why the machine stopped running.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "reason", rename_all = "snake_case")]
+pub enum StopReason {
+    /// `should_run` was cleared, e.g. by Ctrl-C or a hook.
+    Interrupted,
+    /// Firmware made the semihosting EXIT call.
+    Exit { status: u16 },
+    /// An exception that could not be handled, see `VirtualMachine::run`.
+    Fault { exception: Exception, pc: u16 },
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StopReason::Interrupted => return write!(f, "interrupted"),
+            StopReason::Exit { status } => return write!(f, "exit (status {})", status),
+            StopReason::Fault { exception, pc } => {
+                return write!(f, "unhandled {:?} at {:0>4X}", exception, pc)
+            }
+        }
+    }
+}
+
+/// A copy of a block of memory.
+#[derive(Serialize, Deserialize)]
+pub struct MemoryRange {
+    pub start: u16,
+    /// The contents as a string of hex digits, two per byte.
+    #[serde(serialize_with = "serialize_hex", deserialize_with = "deserialize_hex")]
+    pub data: Vec<u8>,
+}
+
+/// A snapshot of the machine, printed as text through `Display` or
+/// exported with `to_json`.
+#[derive(Serialize, Deserialize)]
+pub struct MachineState {
+    pub registers: Registers,
+    /// The set bits of fg, by name.
+    pub flags: Vec<String>,
+    pub cycles: u64,
+    pub stop_reason: Option<StopReason>,
+    pub memory: Vec<MemoryRange>,
+}
+
+impl MachineState {
+    pub fn to_json(&self) -> String {
+        return serde_json::to_string_pretty(self).expect("machine state is serializable");
+    }
+}
+
+/// An executed instruction, as recorded in the trace kept for core files.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct TraceEntry {
+    pub cycle: u64,
+    pub pc: u16,
+    pub opcode: u16,
+}
+
+/// Written when the machine stops on an unhandled fault: the state with the
+/// whole of memory, followed by the last instructions executed, oldest
+/// first.
+#[derive(Serialize, Deserialize)]
+pub struct CoreFile {
+    pub state: MachineState,
+    pub trace: Vec<TraceEntry>,
+}
+
+impl CoreFile {
+    pub fn read(path: &Path) -> Result<Self, String> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) => return Err(format!("cannot read `{}`: {}", path.display(), e)),
+        };
+        return serde_json::from_str(&text)
+            .map_err(|e| format!("invalid core `{}`: {}", path.display(), e));
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), String> {
+        let json = serde_json::to_string(self).expect("core file is serializable");
+        return fs::write(path, json)
+            .map_err(|e| format!("cannot write `{}`: {}", path.display(), e));
+    }
+}
+
+/// Names the bits set in `fg`.
+pub fn decode_flags(fg: u16) -> Vec<String> {
+    let mut flags = Vec::new();
+    for bit in (0..16).rev() {
+        if fg & (1 << bit) == 0 {
+            continue;
+        }
+        match FLAG_NAMES.iter().find(|(b, _)| *b == bit) {
+            Some((_, name)) => flags.push(name.to_string()),
+            None => flags.push(format!("F{}", bit)),
+        }
+    }
+    return flags;
+}
+
+impl fmt::Display for MachineState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let regs = &self.registers;
+        writeln!(f)?;
+        writeln!(f, " ---- VM STATE ----")?;
+        for pair in regs.r.chunks(2).enumerate() {
+            let (i, r) = pair;
+            writeln!(
+                f,
+                " R{}={:0>4X}    R{}={:0>4X}",
+                i * 2,
+                r[0],
+                i * 2 + 1,
+                r[1]
+            )?;
+        }
+        writeln!(f, " C0={:0>4X}    C1={:0>4X}", regs.c[0], regs.c[1])?;
+        writeln!(f, " FG={:0>4X}    SP={:0>4X}", regs.fg, regs.sp)?;
+        writeln!(f, " PC={:0>4X}", regs.pc)?;
+        writeln!(f, " FLAGS={}", self.flags.join(" "))?;
+        writeln!(f, " CYCLES={}", self.cycles)?;
+        if let Some(reason) = &self.stop_reason {
+            writeln!(f, " STOP={}", reason)?;
+        }
+        for range in self.memory.iter() {
+            for (i, line) in range.data.chunks(16).enumerate() {
+                let address = range.start as usize + i * 16;
+                let bytes: Vec<String> = line.iter().map(|b| format!("{:0>2X}", b)).collect();
+                writeln!(f, " {:0>4X}: {}", address, bytes.join(" "))?;
+            }
+        }
+        return Ok(());
+    }
+}
+
+/// Formats `data` as hex digits, two per byte.
+pub(crate) fn to_hex(data: &[u8]) -> String {
+    return data.iter().map(|b| format!("{:0>2x}", b)).collect();
+}
+
+pub(crate) fn parse_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    let mut data = Vec::with_capacity(hex.len() / 2);
+    for i in (0..hex.len()).step_by(2) {
+        data.push(u8::from_str_radix(&hex[i..i + 2], 16).ok()?);
+    }
+    return Some(data);
+}
+
+fn serialize_hex<S: serde::Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    return serializer.serialize_str(&to_hex(data));
+}
+
+fn deserialize_hex<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    let hex = String::deserialize(deserializer)?;
+    return parse_hex(&hex).ok_or_else(|| serde::de::Error::custom("invalid hex data"));
+}